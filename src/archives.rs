@@ -15,17 +15,21 @@
 // You should have received a copy of the GNU General Public License
 // along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
 use std::path::Path;
 
 use flate2::Compression;
+use flate2::read;
 use flate2::write::GzDecoder;
 use flate2::write::GzEncoder;
 use tar::Archive;
 use tar::Builder;
 
+use super::digest;
+
 pub(crate) fn create_tarball(tarball_path: &str, input_files: &str) -> Result<(), String> {
     // Prepare to build an archive
     let mut buf: Vec<u8> = Vec::new();
@@ -99,7 +103,7 @@ pub(crate) fn unpack_tarball(tarball_path: &str, destination_path: &str) -> Resu
 
 pub(crate) fn list_contents(tarball_path: &str) -> Vec<String> {
     let mut return_vector = Vec::new();
-    let mut ar = Archive::new(File::open(&tarball_path).unwrap());
+    let mut ar = Archive::new(read::GzDecoder::new(File::open(tarball_path).unwrap()));
     let ar_entries = ar.entries().unwrap();
     for item in ar_entries {
         let file = item.unwrap();
@@ -107,4 +111,63 @@ pub(crate) fn list_contents(tarball_path: &str) -> Vec<String> {
         return_vector.push(file_path.to_str().unwrap().to_string())
     }
     return return_vector;
-}
\ No newline at end of file
+}
+
+/// SHA-256 digest and size of every file in a tarball, keyed by path
+pub(crate) fn hash_contents(tarball_path: &str) -> Result<HashMap<String, (String, u64)>, String> {
+    let file = match File::open(tarball_path) {
+        Ok(file) => file,
+        Err(issue) => return Err(format!("Failed to open archive <- {}", issue))
+    };
+    let mut ar = Archive::new(read::GzDecoder::new(file));
+    let ar_entries = match ar.entries() {
+        Ok(entries) => entries,
+        Err(issue) => return Err(format!("Failed to read archive <- {}", issue))
+    };
+    let mut return_map = HashMap::new();
+    for item in ar_entries {
+        let mut entry = match item {
+            Ok(entry) => entry,
+            Err(issue) => return Err(format!("Failed to read archive entry <- {}", issue))
+        };
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let file_path = match entry.path() {
+            Ok(path) => path.to_string_lossy().to_string(),
+            Err(issue) => return Err(format!("Invalid path in archive <- {}", issue))
+        };
+        match digest::sha256_reader(&mut entry) {
+            Ok(hashed) => return_map.insert(file_path, hashed),
+            Err(issue) => return Err(format!("Failed to read {} from archive <- {}", file_path, issue))
+        };
+    }
+    Ok(return_map)
+}
+
+/// Pass one file of a tarball to `read`, if the tarball has that file
+pub(crate) fn read_entry<T>(tarball_path: &str, file: &str, read: impl FnOnce(&mut dyn Read) -> T) -> Result<Option<T>, String> {
+    let tarball = match File::open(tarball_path) {
+        Ok(tarball) => tarball,
+        Err(issue) => return Err(format!("Failed to open archive <- {}", issue))
+    };
+    let mut ar = Archive::new(read::GzDecoder::new(tarball));
+    let ar_entries = match ar.entries() {
+        Ok(entries) => entries,
+        Err(issue) => return Err(format!("Failed to read archive <- {}", issue))
+    };
+    for item in ar_entries {
+        let mut entry = match item {
+            Ok(entry) => entry,
+            Err(issue) => return Err(format!("Failed to read archive entry <- {}", issue))
+        };
+        let matches = match entry.path() {
+            Ok(path) => path.to_string_lossy() == file,
+            Err(issue) => return Err(format!("Invalid path in archive <- {}", issue))
+        };
+        if matches {
+            return Ok(Some(read(&mut entry)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,176 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::archives;
+use super::digest;
+use super::file_ownership;
+use super::mods;
+use super::profile::ProfileFile;
+use super::remote;
+
+/// Stands in for files that were in the game directory before pipboy put anything there
+const UNMANAGED: &str = "unmanaged file";
+
+/// One copy of a file that would end up stacked in the game directory
+struct Copy {
+    mod_value: String,
+    /// Empty if the contents were never recorded
+    sha256: String,
+}
+
+/// A path two mods both ship
+struct Overlap {
+    file: String,
+    /// The mod whose copy is, or would be, in the game directory. None if every mod that
+    /// ships the file is disabled.
+    winner: Option<String>,
+    identical: Option<bool>,
+}
+
+/// Make sure a mod's tarball is in the cache so its files can be compared
+fn ensure_cached(config_path: &str, mod_value: &str) -> Result<(), String> {
+    match mods::mod_is_cached(config_path, mod_value) {
+        Ok(true) => Ok(()),
+        Ok(false) => match remote::fetch_mod(config_path, mod_value) {
+            Ok(_) => { println!("Downloaded {} from remote server", mod_value); Ok(()) }
+            Err(issue) => Err(format!("Failed to fetch {} from remote server <- {}", mod_value, issue))
+        },
+        Err(issue) => Err(format!("Failed to search mod cache <- {}", issue))
+    }
+}
+
+/// Stack the copies of every file the installed mods and the candidates provide, in the
+/// order they would be applied
+fn stack_copies(config_path: &str, install_path: &str, candidates: &[String]) -> Result<BTreeMap<String, Vec<Copy>>, String> {
+    let table = match file_ownership::load_ownership_table(config_path) {
+        Ok(table) => table,
+        Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
+    };
+    let mut stacks: BTreeMap<String, Vec<Copy>> = table.files.iter()
+        .map(|(file, providers)| (file.clone(), providers.iter()
+            .map(|provider| Copy { mod_value: provider.mod_value(), sha256: provider.sha256.clone() })
+            .collect()))
+        .collect();
+    for mod_value in candidates {
+        ensure_cached(config_path, mod_value)?;
+        let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mods::mod_directory(mod_value));
+        let contents = match archives::hash_contents(&tarball_path) {
+            Ok(contents) => contents,
+            Err(issue) => return Err(format!("Failed to read {} <- {}", mod_value, issue))
+        };
+        let mod_name = mods::mod_name(mod_value);
+        for (file, (sha256, _)) in contents {
+            let stack = stacks.entry(file.clone()).or_default();
+            // A file nobody owns yet would be overwritten on disk
+            let full_path = format!("{}/{}", install_path, file);
+            if stack.is_empty() && Path::new(&full_path).is_file() {
+                let sha256 = match digest::sha256_file(&full_path) {
+                    Ok(sha256) => sha256,
+                    Err(issue) => return Err(format!("Failed to hash {} <- {}", full_path, issue))
+                };
+                stack.push(Copy { mod_value: UNMANAGED.to_string(), sha256 });
+            }
+            stack.retain(|copy| mods::mod_name(&copy.mod_value) != mod_name);
+            stack.push(Copy { mod_value: mod_value.clone(), sha256 });
+        }
+    }
+    Ok(stacks)
+}
+
+/// Group every path shipped by more than one mod by the pair of mods involved
+///
+/// Without candidates every overlap among installed mods is reported, otherwise only the
+/// overlaps the candidates would add.
+fn find_overlaps(profile: &ProfileFile, stacks: &BTreeMap<String, Vec<Copy>>, candidates: &[String]) -> BTreeMap<(String, String), Vec<Overlap>> {
+    let mut overlaps: BTreeMap<(String, String), Vec<Overlap>> = BTreeMap::new();
+    for (file, stack) in stacks {
+        // Disabled mods keep their place in the stack but never win, as in a deployment
+        let winner = stack.iter().rev()
+            .find(|copy| profile.is_enabled(mods::mod_name(&copy.mod_value)))
+            .map(|copy| copy.mod_value.clone());
+        for (position, lower) in stack.iter().enumerate() {
+            for upper in &stack[position + 1..] {
+                if !candidates.is_empty() && !candidates.contains(&lower.mod_value) && !candidates.contains(&upper.mod_value) {
+                    continue;
+                }
+                let identical = if lower.sha256.is_empty() || upper.sha256.is_empty() {
+                    None
+                } else {
+                    Some(lower.sha256 == upper.sha256)
+                };
+                overlaps.entry((lower.mod_value.clone(), upper.mod_value.clone())).or_default()
+                    .push(Overlap { file: file.clone(), winner: winner.clone(), identical });
+            }
+        }
+    }
+    overlaps
+}
+
+/// Print every file conflict among the installed mods, or every one installing the
+/// candidates would cause
+///
+/// Returns whether any conflicts were found.
+pub(crate) fn show_conflicts(config_path: &str, profile: &ProfileFile, candidates: &[String]) -> Result<bool, String> {
+    let stacks = stack_copies(config_path, &profile.install_path, candidates)?;
+    let overlaps = find_overlaps(profile, &stacks, candidates);
+    if overlaps.is_empty() {
+        println!("No file conflicts.");
+        return Ok(false);
+    }
+    let verb = if candidates.is_empty() { "wins" } else { "would win" };
+    for ((lower, upper), files) in &overlaps {
+        println!("{} <-> {} ({} file{})", lower, upper, files.len(), if files.len() == 1 { "" } else { "s" });
+        for overlap in files {
+            let contents = match overlap.identical {
+                Some(true) => "identical",
+                Some(false) => "different",
+                None => "contents unknown"
+            };
+            match &overlap.winner {
+                Some(winner) => println!("    {}: {} {}, {}", overlap.file, winner, verb, contents),
+                None => println!("    {}: every copy is disabled, {}", overlap.file, contents)
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(mod_value: &str, sha256: &str) -> Copy {
+        Copy { mod_value: mod_value.to_string(), sha256: sha256.to_string() }
+    }
+
+    #[test]
+    fn disabled_mods_never_win() {
+        let mut stacks = BTreeMap::new();
+        stacks.insert("Data/a.esp".to_string(), vec![copy("x/low@1.0.0", "1"), copy("x/high@1.0.0", "2")]);
+        let mut profile = ProfileFile::default();
+        let winner = |profile: &ProfileFile| find_overlaps(profile, &stacks, &[])
+            [&("x/low@1.0.0".to_string(), "x/high@1.0.0".to_string())][0].winner.clone();
+        assert_eq!(winner(&profile), Some("x/high@1.0.0".to_string()));
+        profile.disabled_mods.push("x/high".to_string());
+        assert_eq!(winner(&profile), Some("x/low@1.0.0".to_string()));
+        profile.disabled_mods.push("x/low".to_string());
+        assert_eq!(winner(&profile), None);
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::archives;
+use super::file_ownership;
+use super::file_ownership::{Deployment, OwnershipTable, Provider};
+use super::mods;
+use super::profile::ProfileFile;
+use super::remote;
+use super::transaction::{copy_file, symlink, Transaction};
+
+/// How files get from the staging area into the game directory
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DeployMethod {
+    #[default]
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+impl DeployMethod {
+    pub(crate) fn parse(name: &str) -> Result<DeployMethod, String> {
+        match name {
+            "copy" => Ok(DeployMethod::Copy),
+            "hardlink" => Ok(DeployMethod::Hardlink),
+            "symlink" => Ok(DeployMethod::Symlink),
+            _ => Err(format!("Unknown deployment method \"{}\", expected copy, hardlink or symlink", name))
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DeployMethod::Copy => "copy",
+            DeployMethod::Hardlink => "hardlink",
+            DeployMethod::Symlink => "symlink",
+        }
+    }
+}
+
+/// One change to the game directory
+pub(crate) enum Operation {
+    /// Put a mod's copy of a file in Data. `backup` is set when a file pipboy didn't put
+    /// there has to be saved first.
+    Place { file: String, provider: Provider, method: DeployMethod, backup: bool },
+    /// Take a file out of Data. `restore` is set when there is an original to put back.
+    Remove { file: String, restore: bool },
+}
+
+/// Where a mod is extracted to before being deployed
+pub(crate) fn staging_path(config_path: &str, mod_value: &str) -> String {
+    format!("{}/staging/{}", config_path, mods::mod_directory(mod_value))
+}
+
+/// Extract a mod into its staging directory unless that was already done
+pub(crate) fn stage_mod(config_path: &str, mod_value: &str) -> Result<(), String> {
+    let staging_path = staging_path(config_path, mod_value);
+    if Path::new(&staging_path).exists() {
+        return Ok(());
+    }
+    if !mods::mod_is_cached(config_path, mod_value)? {
+        match remote::fetch_mod(config_path, mod_value) {
+            Ok(_) => println!("Downloaded {} from remote server", mod_value),
+            Err(issue) => return Err(format!("Failed to fetch mod from remote server <- {}", issue))
+        }
+    }
+    let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mods::mod_directory(mod_value));
+    // Extract next to the staging directory so an interrupted extraction isn't mistaken for a finished one
+    let partial_path = format!("{}.partial", staging_path);
+    if Path::new(&partial_path).exists() && fs::remove_dir_all(&partial_path).is_err() {
+        return Err(format!("Failed to remove {}", partial_path));
+    }
+    if fs::create_dir_all(&partial_path).is_err() {
+        return Err(format!("Failed to create path {}", partial_path));
+    }
+    if let Err(issue) = archives::unpack_tarball(&tarball_path, &partial_path) {
+        return Err(format!("Failed to extract {} <- {}", mod_value, issue));
+    }
+    match fs::rename(&partial_path, &staging_path) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Failed to move {} to {}", partial_path, staging_path))
+    }
+}
+
+/// Throw away the extracted copy of a mod, so the next deployment extracts it again
+pub(crate) fn unstage_mod(config_path: &str, mod_value: &str) -> Result<(), String> {
+    let staging_path = staging_path(config_path, mod_value);
+    if Path::new(&staging_path).exists() && fs::remove_dir_all(&staging_path).is_err() {
+        return Err(format!("Failed to remove {}", staging_path));
+    }
+    Ok(())
+}
+
+/// Whether Data was purged, leaving the files of the enabled mods out of it
+pub(crate) fn is_purged(config_path: &str, profile: &ProfileFile) -> Result<bool, String> {
+    let table = file_ownership::load_ownership_table(config_path)?;
+    Ok(table.deployed.is_empty() && table.files.values().flatten().any(|provider| profile.is_enabled(&provider.owner)))
+}
+
+/// Remove the staged copy of a mod or version that left the profile
+///
+/// Only call this once Data no longer links to the copy. A rollback throws away whatever is
+/// left of it, and the next deployment extracts it again if it is needed.
+pub(crate) fn discard_staged(config_path: &str, mod_value: &str, transaction: &mut Transaction) -> Result<(), String> {
+    transaction.discard_on_rollback(&staging_path(config_path, mod_value))?;
+    unstage_mod(config_path, mod_value)
+}
+
+/// Where a file that was in the game directory before pipboy replaced it is kept
+fn vanilla_backup_path(config_path: &str, file: &str) -> Result<String, String> {
+    Ok(format!("{}/backups/vanilla/{}", file_ownership::profile_directory(config_path)?, file))
+}
+
+/// Whether two records describe the same copy of a file
+fn same_copy(a: &Provider, b: &Provider) -> bool {
+    a.owner == b.owner && a.version == b.version && a.sha256 == b.sha256
+}
+
+/// The method a deployment can actually use. Hardlinks can't cross filesystems, so those
+/// fall back to copying.
+fn usable_method(config_path: &str, install_path: &str, method: DeployMethod) -> DeployMethod {
+    if method != DeployMethod::Hardlink {
+        return method;
+    }
+    let staging_root = format!("{}/staging", config_path);
+    let _ = fs::create_dir_all(&staging_root);
+    match same_filesystem(&staging_root, install_path) {
+        Some(true) => method,
+        _ => {
+            println!("{} is not on the same filesystem as {}, copying files instead of hardlinking them", staging_root, install_path);
+            DeployMethod::Copy
+        }
+    }
+}
+
+/// Whether two paths are on the same filesystem, if that can be told
+#[cfg(unix)]
+fn same_filesystem(first: &str, second: &str) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(first), fs::metadata(second)) {
+        (Ok(first), Ok(second)) => Some(first.dev() == second.dev()),
+        _ => None
+    }
+}
+
+/// Whether two paths are on the same filesystem, if that can be told
+#[cfg(not(unix))]
+fn same_filesystem(first: &str, second: &str) -> Option<bool> {
+    // Linking across filesystems still falls back to copying when it fails
+    match (fs::metadata(first), fs::metadata(second)) {
+        (Ok(_), Ok(_)) => Some(true),
+        _ => None
+    }
+}
+
+fn remove_operation(config_path: &str, file: &str) -> Result<Operation, String> {
+    let restore = Path::new(&vanilla_backup_path(config_path, file)?).exists();
+    Ok(Operation::Remove { file: file.to_string(), restore })
+}
+
+/// Work out what has to change for Data to hold the highest priority copy of every file,
+/// put there with the given method
+fn plan_deployment(config_path: &str, table: &OwnershipTable, install_path: &str, disabled_mods: &[String], method: DeployMethod) -> Result<Vec<Operation>, String> {
+    let mut operations = Vec::new();
+    let mut unwanted = Vec::new();
+    for (file, providers) in &table.files {
+        // Disabled mods keep their place in the table but never win
+        let wanted = match providers.iter().rev().find(|provider| !disabled_mods.contains(&provider.owner)) {
+            Some(provider) => provider,
+            None => {
+                unwanted.push(file);
+                continue;
+            }
+        };
+        let on_disk = Path::new(&format!("{}/{}", install_path, file)).is_file();
+        match table.deployed.get(file) {
+            Some(deployed) if same_copy(&deployed.provider, wanted) && deployed.method == method && on_disk => {}
+            deployed => operations.push(Operation::Place {
+                file: file.clone(),
+                provider: wanted.clone(),
+                method,
+                backup: deployed.is_none() && on_disk,
+            })
+        }
+    }
+    for file in table.deployed.keys() {
+        if table.providers(file).is_empty() || unwanted.contains(&file) {
+            operations.push(remove_operation(config_path, file)?);
+        }
+    }
+    Ok(operations)
+}
+
+/// Work out what has to change to take every managed file out of Data
+fn plan_purge(config_path: &str, table: &OwnershipTable) -> Result<Vec<Operation>, String> {
+    table.deployed.keys().map(|file| remove_operation(config_path, file)).collect()
+}
+
+/// Whether anything, including a dangling symlink, is at a path
+fn path_taken(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Put a staged file in the game directory, returning the method that was used
+///
+/// Hardlinking falls back to copying when the two paths turn out to be on different filesystems.
+fn place_file(source: &str, destination: &str, method: DeployMethod) -> Result<DeployMethod, String> {
+    if let Some(parent) = Path::new(destination).parent() {
+        if fs::create_dir_all(parent).is_err() {
+            return Err(format!("Failed to create path {}", parent.display()));
+        }
+    }
+    // Writing through a link would change the staged copy it points to
+    if path_taken(destination) && fs::remove_file(destination).is_err() {
+        return Err(format!("Failed to remove file {}", destination));
+    }
+    let linked: io::Result<()> = match method {
+        DeployMethod::Copy => return copy_file(source, destination).map(|_| DeployMethod::Copy),
+        DeployMethod::Hardlink => fs::hard_link(source, destination),
+        DeployMethod::Symlink => match fs::canonicalize(source) {
+            Ok(target) => symlink(&target, Path::new(destination)),
+            Err(issue) => Err(issue)
+        }
+    };
+    match linked {
+        Ok(_) => Ok(method),
+        Err(issue) if method == DeployMethod::Hardlink && issue.kind() == io::ErrorKind::CrossesDevices => {
+            // The staging area and the game directory are on different filesystems
+            copy_file(source, destination)?;
+            Ok(DeployMethod::Copy)
+        }
+        Err(issue) => Err(format!("Failed to {} {} to {} <- {}", method.name(), source, destination, issue))
+    }
+}
+
+fn apply(config_path: &str, install_path: &str, table: &mut OwnershipTable, operation: &Operation, transaction: &mut Transaction) -> Result<(), String> {
+    match operation {
+        Operation::Place { file, provider, method, backup } => {
+            let full_path = format!("{}/{}", install_path, file);
+            if *backup {
+                let backup_path = vanilla_backup_path(config_path, file)?;
+                // Never replace the original with a copy of something else
+                if !Path::new(&backup_path).exists() {
+                    transaction.stage_path(&backup_path)?;
+                    copy_file(&full_path, &backup_path)?;
+                }
+            }
+            let mod_value = provider.mod_value();
+            stage_mod(config_path, &mod_value)?;
+            transaction.stage_files(&[file])?;
+            let method = place_file(&format!("{}/{}", staging_path(config_path, &mod_value), file), &full_path, *method)?;
+            table.deployed.insert(file.clone(), Deployment { provider: provider.clone(), method });
+        }
+        Operation::Remove { file, .. } => {
+            let full_path = format!("{}/{}", install_path, file);
+            transaction.stage_files(&[file])?;
+            // Only the link itself goes away, never the staged copy behind it
+            if path_taken(&full_path) && fs::remove_file(&full_path).is_err() {
+                return Err(format!("Failed to remove file {}", full_path));
+            }
+            let backup_path = vanilla_backup_path(config_path, file)?;
+            if Path::new(&backup_path).exists() {
+                transaction.stage_path(&backup_path)?;
+                copy_file(&backup_path, &full_path)?;
+                if fs::remove_file(&backup_path).is_err() {
+                    return Err(format!("Failed to remove {}", backup_path));
+                }
+            }
+            table.deployed.remove(file);
+        }
+    }
+    Ok(())
+}
+
+fn apply_all(config_path: &str, install_path: &str, table: &mut OwnershipTable, operations: &[Operation], transaction: &mut Transaction) -> Result<(), String> {
+    for operation in operations {
+        let result = apply(config_path, install_path, table, operation, transaction);
+        // Record what did happen, even if something went wrong partway
+        if let Err(issue) = result {
+            file_ownership::save_ownership_table(config_path, table)?;
+            return Err(issue);
+        }
+    }
+    file_ownership::save_ownership_table(config_path, table)
+}
+
+/// Work out what a deployment would do without touching anything
+pub(crate) fn preview_deployment(config_path: &str, profile: &ProfileFile) -> Result<Vec<Operation>, String> {
+    let mut table = file_ownership::load_ownership_table(config_path)?;
+    table.apply_priorities(&profile.mod_order);
+    let method = usable_method(config_path, &profile.install_path, profile.deploy_method);
+    plan_deployment(config_path, &table, &profile.install_path, &profile.disabled_mods, method)
+}
+
+/// Bring the game directory in line with the ownership table, giving each file to its
+/// highest priority enabled provider
+///
+/// Returns the operations that were carried out.
+pub(crate) fn deploy(config_path: &str, profile: &ProfileFile, transaction: &mut Transaction) -> Result<Vec<Operation>, String> {
+    let mut table = file_ownership::load_ownership_table(config_path)?;
+    table.apply_priorities(&profile.mod_order);
+    let method = usable_method(config_path, &profile.install_path, profile.deploy_method);
+    let operations = plan_deployment(config_path, &table, &profile.install_path, &profile.disabled_mods, method)?;
+    apply_all(config_path, &profile.install_path, &mut table, &operations, transaction)?;
+    Ok(operations)
+}
+
+/// Work out what a purge would do without touching anything
+pub(crate) fn preview_purge(config_path: &str) -> Result<Vec<Operation>, String> {
+    plan_purge(config_path, &file_ownership::load_ownership_table(config_path)?)
+}
+
+/// Take every managed file out of the game directory and put back the files they replaced
+///
+/// Mods stay installed, so a deployment puts them back. Returns the operations that were carried out.
+pub(crate) fn purge(config_path: &str, install_path: &str, transaction: &mut Transaction) -> Result<Vec<Operation>, String> {
+    let mut table = file_ownership::load_ownership_table(config_path)?;
+    let operations = plan_purge(config_path, &table)?;
+    apply_all(config_path, install_path, &mut table, &operations, transaction)?;
+    Ok(operations)
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}
+
+/// Print what a deployment or purge did, or would do for a dry run, one line per file when verbose
+pub(crate) fn print_summary(operations: &[Operation], verbose: bool, dry_run: bool) {
+    let (mut placed, mut backed_up, mut removed, mut restored) = (0, 0, 0, 0);
+    for operation in operations {
+        match operation {
+            Operation::Place { file, provider, method, backup } => {
+                placed += 1;
+                if *backup {
+                    backed_up += 1;
+                }
+                if verbose || dry_run {
+                    println!("{} {} from {} ({}){}", if dry_run { "Would deploy" } else { "Deployed" }, file,
+                        provider.mod_value(), method.name(), if *backup { ", backing up the original" } else { "" });
+                }
+            }
+            Operation::Remove { file, restore } => {
+                removed += 1;
+                if *restore {
+                    restored += 1;
+                }
+                if verbose || dry_run {
+                    println!("{} {}{}", if dry_run { "Would remove" } else { "Removed" }, file,
+                        if *restore { ", restoring the original" } else { "" });
+                }
+            }
+        }
+    }
+    println!("{} {} file{} ({} original{} backed up), {} {} file{} ({} original{} restored)",
+        if dry_run { "Would deploy" } else { "Deployed" }, placed, plural(placed), backed_up, plural(backed_up),
+        if dry_run { "remove" } else { "removed" }, removed, plural(removed), restored, plural(restored));
+}
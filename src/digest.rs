@@ -0,0 +1,55 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+/// Lowercase hex encoding of a SHA-256 digest
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-256 digest of a buffer
+pub(crate) fn sha256_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// SHA-256 digest of everything a reader yields, along with the number of bytes read
+pub(crate) fn sha256_reader<R: Read>(reader: &mut R) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(reader, &mut hasher)?;
+    Ok((to_hex(&hasher.finalize()), size))
+}
+
+/// SHA-256 digest of a file, read without loading it into memory
+pub(crate) fn sha256_file(path: &str) -> Result<String, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(issue) => return Err(format!("Failed to open {} <- {}", path, issue))
+    };
+    match sha256_reader(&mut file) {
+        Ok((digest, _)) => Ok(digest),
+        Err(issue) => Err(format!("Failed to read {} <- {}", path, issue))
+    }
+}
+
+/// Whether a string looks like a hex encoded SHA-256 digest
+pub(crate) fn is_sha256(digest: &str) -> bool {
+    digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -15,51 +15,225 @@
 // You should have received a copy of the GNU General Public License
 // along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
 
+use super::archives;
 use super::config_file;
+use super::deploy::DeployMethod;
 use super::mods;
 
+use serde::{Serialize, Deserialize};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-fn save_ownership_hashmap(config_path: &str, new_map: HashMap<String, String>) -> Result<(), String> {
-    let config_file = match config_file::load_config_file(&config_path) {
-        Ok(config) => config,
-        Err(_) => return Err("Failed to load configuration file!".to_string())
-    };
-    let ownership_path = format!("{}/profiles/{}/file_ownership.json", &config_path, &config_file.current_profile);
+/// Layout version of `file_ownership.json` written by this version of pipboy
+const OWNERSHIP_TABLE_VERSION: u32 = 3;
+
+/// One mod's copy of a managed file
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Provider {
+    /// The mod that ships the file, formatted author/mod
+    pub(crate) owner: String,
+    /// Version of the mod that was installed, empty for unversioned mods
+    #[serde(default)]
+    pub(crate) version: String,
+    /// Hex encoded SHA-256 digest of the mod's copy, empty if it was never recorded
+    #[serde(default)]
+    pub(crate) sha256: String,
+    #[serde(default)]
+    pub(crate) size: u64,
+    /// Seconds since the Unix epoch at which the copy was installed, 0 if unknown
+    #[serde(default)]
+    pub(crate) installed_at: u64,
+}
+
+impl Provider {
+    /// The mod value of the version of the mod that provided the file
+    pub(crate) fn mod_value(&self) -> String {
+        if self.version.is_empty() {
+            self.owner.clone()
+        } else {
+            format!("{}@{}", self.owner, self.version)
+        }
+    }
+}
+
+/// A copy of a file that was put in Data
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Deployment {
+    #[serde(flatten)]
+    pub(crate) provider: Provider,
+    /// How the copy got there, which decides how it is safely taken out again
+    #[serde(default)]
+    pub(crate) method: DeployMethod,
+}
+
+/// Every mod that provides each managed file
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OwnershipTable {
+    pub(crate) version: u32,
+    /// Providers of each path from lowest to highest priority. The last one's copy belongs in Data.
+    pub(crate) files: BTreeMap<String, Vec<Provider>>,
+    /// The copy of each path that was last deployed to Data
+    #[serde(default)]
+    pub(crate) deployed: BTreeMap<String, Deployment>,
+}
+
+impl OwnershipTable {
+    fn new() -> OwnershipTable {
+        OwnershipTable { version: OWNERSHIP_TABLE_VERSION, files: BTreeMap::new(), deployed: BTreeMap::new() }
+    }
+
+    /// Every mod that ships a file, from lowest to highest priority
+    pub(crate) fn providers(&self, file: &str) -> &[Provider] {
+        match self.files.get(file) {
+            Some(providers) => providers,
+            None => &[]
+        }
+    }
+
+    /// Sort the providers of every file by the priority order, lowest first
+    pub(crate) fn apply_priorities(&mut self, mod_order: &[String]) {
+        for providers in self.files.values_mut() {
+            // Providers pipboy no longer has a priority for stay beneath the others
+            providers.sort_by_key(|provider| mod_order.iter().position(|item| *item == provider.owner));
+        }
+    }
+}
+
+/// Entries of ownership tables from before the table was versioned
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyOwners {
+    One(String),
+    Stack(Vec<String>),
+}
+
+/// Convert an unversioned table of path to owner, or path to stack of owners
+fn migrate_legacy_table(legacy: HashMap<String, LegacyOwners>) -> OwnershipTable {
+    let mut files = BTreeMap::new();
+    for (file, owners) in legacy {
+        let owners = match owners {
+            LegacyOwners::One(owner) => vec![owner],
+            LegacyOwners::Stack(owners) => owners,
+        };
+        let providers = owners.into_iter()
+            .map(|owner| Provider { owner, version: String::new(), sha256: String::new(), size: 0, installed_at: 0 })
+            .collect();
+        files.insert(file, providers);
+    }
+    OwnershipTable { version: OWNERSHIP_TABLE_VERSION, files, deployed: BTreeMap::new() }
+}
+
+/// Older tables didn't record deployments, so assume the top provider of each file is in Data
+fn fill_deployed(table: &mut OwnershipTable) {
+    for (file, providers) in &table.files {
+        if let Some(top) = providers.last() {
+            table.deployed.insert(file.clone(), Deployment { provider: top.clone(), method: DeployMethod::Copy });
+        }
+    }
+    table.version = OWNERSHIP_TABLE_VERSION;
+}
+
+pub(crate) fn profile_directory(config_path: &str) -> Result<String, String> {
+    match config_file::load_config_file(config_path) {
+        Ok(config) => Ok(format!("{}/profiles/{}", config_path, config.current_profile)),
+        Err(_) => Err("Failed to load configuration file!".to_string())
+    }
+}
+
+pub(crate) fn save_ownership_table(config_path: &str, table: &OwnershipTable) -> Result<(), String> {
+    let ownership_path = format!("{}/file_ownership.json", profile_directory(config_path)?);
     // Serialize the dictionary
-    let j = serde_json::to_string(&new_map).unwrap();
-    return match fs::write(&ownership_path, &j) {
+    let j = serde_json::to_string(table).unwrap();
+    match fs::write(&ownership_path, &j) {
         Ok(_) => Ok(()),
         Err(_) => Err("Failed to write ownership dictionary to disk.".to_string())
     }
 }
 
-fn load_ownership_hashmap(config_path: &str) -> Result<HashMap<String, String>, String> {
-    let config_file = match config_file::load_config_file(&config_path) {
-        Ok(config) => config,
-        Err(_) => return Err("Failed to load configuration file!".to_string())
+/// Parse an ownership table, migrating tables written by older versions
+///
+/// Returns the table and whether it was migrated.
+fn parse_ownership_table(j: &str) -> Result<(OwnershipTable, bool), String> {
+    let value: serde_json::Value = match serde_json::from_str(j) {
+        Ok(value) => value,
+        Err(issue) => return Err(format!("Malformed file ownership table <- {}", issue))
     };
-    let ownership_path = format!("{}/profiles/{}/file_ownership.json", &config_path, &config_file.current_profile);
-    // Define the map that will be returned
-    let mut return_map = HashMap::new();
-    // Populate the return map
-    if Path::new(&ownership_path).exists() {
-        let j: String = fs::read_to_string(&ownership_path).unwrap().parse().unwrap();
-        let dict_load: HashMap<String, String> = serde_json::from_str(&j).unwrap();
-        for item in dict_load {
-            return_map.insert(item.0, item.1);
+    match value.get("version").and_then(|version| version.as_u64()) {
+        Some(version) if version > OWNERSHIP_TABLE_VERSION as u64 => {
+            Err(format!("File ownership table version {} is newer than this version of pipboy supports", version))
+        }
+        Some(version) => {
+            let mut table: OwnershipTable = match serde_json::from_value(value) {
+                Ok(table) => table,
+                Err(issue) => return Err(format!("Malformed file ownership table <- {}", issue))
+            };
+            let migrated = version < OWNERSHIP_TABLE_VERSION as u64;
+            if migrated {
+                fill_deployed(&mut table);
+            }
+            Ok((table, migrated))
+        }
+        None => {
+            let legacy: HashMap<String, LegacyOwners> = match serde_json::from_value(value) {
+                Ok(legacy) => legacy,
+                Err(issue) => return Err(format!("Malformed file ownership table <- {}", issue))
+            };
+            let mut table = migrate_legacy_table(legacy);
+            fill_deployed(&mut table);
+            Ok((table, true))
         }
     }
-    Ok(return_map)
+}
+
+/// Load the current profile's ownership table, migrating tables written by older versions
+pub(crate) fn load_ownership_table(config_path: &str) -> Result<OwnershipTable, String> {
+    let ownership_path = format!("{}/file_ownership.json", profile_directory(config_path)?);
+    if !Path::new(&ownership_path).exists() {
+        return Ok(OwnershipTable::new());
+    }
+    let j: String = match fs::read_to_string(&ownership_path) {
+        Ok(j) => j,
+        Err(_) => return Err(format!("Failed to read {}", ownership_path))
+    };
+    let (table, migrated) = parse_ownership_table(&j)?;
+    if migrated {
+        save_ownership_table(config_path, &table)?;
+        println!("Migrated file ownership table to version {}", OWNERSHIP_TABLE_VERSION);
+    }
+    Ok(table)
+}
+
+/// Describe one version of a mod's copies of its files, as found in its cached tarball
+fn describe_providers(config_path: &str, mod_value: &str) -> Result<HashMap<String, Provider>, String> {
+    let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mods::mod_directory(mod_value));
+    let contents = archives::hash_contents(&tarball_path)?;
+    let installed_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0
+    };
+    Ok(contents.into_iter()
+        .map(|(file, (sha256, size))| (file, Provider {
+            owner: mods::mod_name(mod_value).to_string(),
+            version: mods::mod_version(mod_value).unwrap_or("").to_string(),
+            sha256,
+            size,
+            installed_at,
+        }))
+        .collect())
+}
+
+/// The mod whose copy of a file is currently in the game directory
+pub(crate) fn current_owner<'a>(table: &'a OwnershipTable, file: &str) -> Option<&'a str> {
+    table.deployed.get(file).map(|deployment| deployment.provider.owner.as_str())
 }
 
 pub(crate) fn installation_update(config_path: &str, mod_value: &str, verbose: &bool) -> Result<(), String> {
-    // Get existing HashMap
-    let mut ownership_map = match load_ownership_hashmap(&config_path) {
-        Ok(map) => map,
+    // Get existing table
+    let mut table = match load_ownership_table(config_path) {
+        Ok(table) => table,
         Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
     };
     // Generate mod index if it doesn't exist
@@ -74,30 +248,40 @@ pub(crate) fn installation_update(config_path: &str, mod_value: &str, verbose: &
         Ok(index) => index,
         Err(issue) => return Err(format!("Failed to get mod index <- {}", issue))
     };
-    // If we've gotten this far, the user has either ignored checking or the ownership
-    // table is out of sync, so it's safe to just nuke and overwrite duplicate entries.
+    let mut descriptions = describe_providers(config_path, mod_value)?;
+    let mod_name = mods::mod_name(mod_value);
     for file in mod_index.lines() {
-        // Remove the value if the file was already in use by another mod
-        if ownership_map.contains_key(&*file) {
-            ownership_map.remove(&*file);
-        }
-        // Define the file's owner
-        ownership_map.insert(
-            file.to_string(),
-            mod_value.to_string(),
-        );
+        let provider = match descriptions.remove(file) {
+            Some(provider) => provider,
+            None => continue
+        };
+        // New mods go on top until the next deployment sorts them by priority
+        let providers = table.files.entry(file.to_string()).or_default();
+        providers.retain(|provider| provider.owner != mod_name);
+        providers.push(provider);
     }
-    // Serialize the dictionary
-    return match save_ownership_hashmap(&config_path, ownership_map) {
+    match save_ownership_table(config_path, &table) {
         Ok(_) => Ok(()),
-        Err(issue) => return Err(format!("Failed to save ownership dictionary <- {}", issue))
-    };
+        Err(issue) => Err(format!("Failed to save ownership dictionary <- {}", issue))
+    }
+}
+
+/// Remove a mod from the providers of the given files
+fn release_ownership(table: &mut OwnershipTable, mod_name: &str, files: &[&str]) {
+    for file in files {
+        if let Some(providers) = table.files.get_mut(*file) {
+            providers.retain(|provider| provider.owner != mod_name);
+            if providers.is_empty() {
+                table.files.remove(*file);
+            }
+        }
+    }
 }
 
 pub(crate) fn uninstallation_update(config_path: &str, mod_value: &str) -> Result<(), String> {
-    // Get existing HashMap
-    let mut ownership_map = match load_ownership_hashmap(&config_path) {
-        Ok(map) => map,
+    // Get existing table
+    let mut table = match load_ownership_table(config_path) {
+        Ok(table) => table,
         Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
     };
     // Get index for mod
@@ -105,15 +289,97 @@ pub(crate) fn uninstallation_update(config_path: &str, mod_value: &str) -> Resul
         Ok(index) => index,
         Err(issue) => return Err(format!("Failed to get mod index <- {}", issue))
     };
-    for file in mod_index.lines() {
-        if ownership_map.contains_key(&*file) {
-            if ownership_map.get(&*file).unwrap() == &mod_value {
-                ownership_map.remove(&*file);
-            }
+    let files: Vec<&str> = mod_index.lines().collect();
+    release_ownership(&mut table, mods::mod_name(mod_value), &files);
+    match save_ownership_table(config_path, &table) {
+        Ok(_) => Ok(()),
+        Err(issue) => Err(format!("Failed to save ownership table <- {}", issue))
+    }
+}
+
+/// Give up ownership of the files one version of a mod shipped and take ownership of the
+/// files of the version replacing it
+///
+/// Files both versions ship keep their place among the file's providers.
+pub(crate) fn upgrade_update(config_path: &str, old_files: &[&str], new_value: &str, new_files: &[&str]) -> Result<(), String> {
+    let mut table = match load_ownership_table(config_path) {
+        Ok(table) => table,
+        Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
+    };
+    let mod_name = mods::mod_name(new_value);
+    let dropped: Vec<&str> = old_files.iter().filter(|file| !new_files.contains(file)).cloned().collect();
+    release_ownership(&mut table, mod_name, &dropped);
+    let mut descriptions = describe_providers(config_path, new_value)?;
+    for file in new_files {
+        let provider = match descriptions.remove(*file) {
+            Some(provider) => provider,
+            None => continue
+        };
+        let providers = table.files.entry(file.to_string()).or_default();
+        match providers.iter_mut().find(|existing| existing.owner == mod_name) {
+            Some(existing) => *existing = provider,
+            None => providers.push(provider)
         }
     }
-    return match save_ownership_hashmap(&config_path, ownership_map) {
+    match save_ownership_table(config_path, &table) {
         Ok(_) => Ok(()),
         Err(issue) => Err(format!("Failed to save ownership table <- {}", issue))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(table: &OwnershipTable, file: &str) -> Vec<String> {
+        table.providers(file).iter().map(|provider| provider.owner.clone()).collect()
+    }
+
+    fn deployed_owner(table: &OwnershipTable, file: &str) -> Option<String> {
+        current_owner(table, file).map(|owner| owner.to_string())
+    }
+
+    #[test]
+    fn single_owner_tables_are_migrated() {
+        let (table, migrated) = parse_ownership_table(r#"{"Data/a.esp": "someone/mod"}"#).unwrap();
+        assert!(migrated);
+        assert_eq!(table.version, OWNERSHIP_TABLE_VERSION);
+        assert_eq!(owners(&table, "Data/a.esp"), vec!["someone/mod"]);
+        assert_eq!(deployed_owner(&table, "Data/a.esp"), Some("someone/mod".to_string()));
+    }
+
+    #[test]
+    fn owner_stacks_are_migrated_with_the_top_owner_deployed() {
+        let (table, migrated) = parse_ownership_table(r#"{"Data/a.esp": ["someone/low", "someone/high"]}"#).unwrap();
+        assert!(migrated);
+        assert_eq!(owners(&table, "Data/a.esp"), vec!["someone/low", "someone/high"]);
+        assert_eq!(deployed_owner(&table, "Data/a.esp"), Some("someone/high".to_string()));
+        assert_eq!(table.providers("Data/a.esp")[0].sha256, "");
+    }
+
+    #[test]
+    fn tables_without_deployments_are_migrated() {
+        let (table, migrated) = parse_ownership_table(r#"{"version": 2, "files": {"Data/a.esp": [
+            {"owner": "someone/mod", "version": "1.0.0", "sha256": "abc", "size": 3, "installed_at": 10}
+        ]}}"#).unwrap();
+        assert!(migrated);
+        assert_eq!(table.version, OWNERSHIP_TABLE_VERSION);
+        let deployment = &table.deployed["Data/a.esp"];
+        assert_eq!(deployment.provider.mod_value(), "someone/mod@1.0.0");
+        assert_eq!(deployment.provider.sha256, "abc");
+    }
+
+    #[test]
+    fn current_tables_are_left_alone() {
+        let j = format!(r#"{{"version": {}, "files": {{"Data/a.esp": [{{"owner": "someone/mod"}}]}}, "deployed": {{}}}}"#, OWNERSHIP_TABLE_VERSION);
+        let (table, migrated) = parse_ownership_table(&j).unwrap();
+        assert!(!migrated);
+        assert!(table.deployed.is_empty());
+    }
+
+    #[test]
+    fn newer_tables_are_refused() {
+        let j = format!(r#"{{"version": {}, "files": {{}}}}"#, OWNERSHIP_TABLE_VERSION + 1);
+        assert!(parse_ownership_table(&j).is_err());
+    }
+}
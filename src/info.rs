@@ -0,0 +1,184 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use semver::Version;
+use std::path::Path;
+
+use super::archives;
+use super::deploy;
+use super::file_ownership;
+use super::mods;
+use super::plugins;
+use super::profile::ProfileFile;
+use super::remote;
+use super::tes4;
+use super::tes4::PluginHeader;
+
+/// Format a byte count for humans
+pub(crate) fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Read the header of a plugin a mod ships, from its staged copy if there is one
+fn read_plugin_header(config_path: &str, mod_value: &str, file: &str) -> Result<PluginHeader, String> {
+    let staged = format!("{}/{}", deploy::staging_path(config_path, mod_value), file);
+    if Path::new(&staged).is_file() {
+        return tes4::read_header(&staged);
+    }
+    let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mods::mod_directory(mod_value));
+    match archives::read_entry(&tarball_path, file, |entry| tes4::parse_header(entry))? {
+        Some(header) => header,
+        None => Err(format!("{} is not in the archive", file))
+    }
+}
+
+/// Print everything pipboy knows about a mod
+pub(crate) fn show_info(config_path: &str, remotes: &[String], profile: &ProfileFile, mod_value: &str) -> Result<(), String> {
+    let mod_name = mods::mod_name(mod_value);
+    let installed = profile.enabled_mods.iter().any(|installed| installed == mod_name);
+    // Without an explicit version describe the installed version, or else the newest one
+    let mod_value = if mods::mod_version(mod_value).is_none() && installed {
+        profile.installed_value(mod_name)
+    } else {
+        mod_value.to_string()
+    };
+    let entry = remote::find_index_entry(config_path, remotes, &mod_value);
+    let mod_value = match &entry {
+        Some((_, entry)) => entry.versioned_value(),
+        None => mod_value
+    };
+    let cached = mods::mod_is_cached(config_path, &mod_value)?;
+    if entry.is_none() && !cached && !installed {
+        return Err(format!("{} was not found in any cached repository index", mod_value));
+    }
+    println!("Mod: {}", mod_name);
+    match &entry {
+        Some((server, entry)) => {
+            println!("Repository: {}", server);
+            if !entry.version.is_empty() {
+                println!("Version: {}", entry.version);
+            }
+            if entry.size > 0 {
+                println!("Size: {}", format_size(entry.size));
+            }
+            if !entry.tags.is_empty() {
+                println!("Tags: {}", entry.tags.join(", "));
+            }
+            if !entry.description.is_empty() {
+                println!("Description: {}", entry.description);
+            }
+        }
+        None => println!("Repository: none (not in any cached index)")
+    }
+    let mut available: Vec<Version> = remote::find_mod_versions(config_path, remotes, mod_name).iter()
+        .filter_map(|(_, entry)| entry.parsed_version())
+        .collect();
+    if !available.is_empty() {
+        available.sort();
+        available.dedup();
+        let available: Vec<String> = available.iter().map(|version| version.to_string()).collect();
+        println!("Available versions: {}", available.join(", "));
+    }
+    println!("Cached: {}", if cached { "yes" } else { "no" });
+    if installed {
+        let state = if profile.is_enabled(mod_name) { "" } else { ", disabled" };
+        println!("Installed: yes ({}{})", profile.installed_value(mod_name), state);
+    } else {
+        println!("Installed: no");
+    }
+    // Dependencies in both directions
+    // The installed version's dependencies were recorded when it was installed
+    let depends = match profile.mod_depends.get(mod_name) {
+        Some(depends) if installed && profile.installed_value(mod_name) == mod_value => depends.clone(),
+        _ => match remote::fetch_mod_depends(config_path, remotes, &mod_value) {
+            Ok(depends) => depends,
+            Err(issue) => return Err(format!("Failed to find dependencies of {} <- {}", mod_value, issue))
+        }
+    };
+    if depends.is_empty() {
+        println!("Depends on: nothing");
+    } else {
+        println!("Depends on: {}", depends.join(", "));
+    }
+    let required_by = profile.reverse_depends(mod_name);
+    if required_by.is_empty() {
+        println!("Required by: nothing installed");
+    } else {
+        println!("Required by: {}", required_by.join(", "));
+    }
+    // Only list files if the mod doesn't have to be downloaded to do so
+    if !cached && !mods::mod_has_index(config_path, &mod_value) {
+        println!("Files: unknown until the mod is downloaded");
+        return Ok(());
+    }
+    let mod_index = match mods::load_index(config_path, &mod_value) {
+        Ok(index) => index,
+        Err(issue) => return Err(format!("Failed to load index for {} <- {}", mod_value, issue))
+    };
+    let table = match file_ownership::load_ownership_table(config_path) {
+        Ok(map) => map,
+        Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
+    };
+    println!("Files:");
+    for file in mod_index.lines() {
+        match file_ownership::current_owner(&table, file) {
+            Some(owner) if owner != mod_name => println!("    {} (owned by {})", file, owner),
+            _ => println!("    {}", file)
+        }
+    }
+    let plugin_files: Vec<&str> = mod_index.lines().filter(|file| plugins::plugin_name(file).is_some()).collect();
+    if plugin_files.is_empty() {
+        return Ok(());
+    }
+    println!("Plugins:");
+    for file in plugin_files {
+        let name = plugins::plugin_name(file).unwrap_or(file);
+        // Reading a plugin from the archive needs the archive
+        if !cached && !Path::new(&deploy::staging_path(config_path, &mod_value)).exists() {
+            println!("    {} (masters unknown until the mod is downloaded)", name);
+            continue;
+        }
+        let header = match read_plugin_header(config_path, &mod_value, file) {
+            Ok(header) => header,
+            Err(issue) => { println!("    {} (unreadable <- {})", name, issue); continue; }
+        };
+        println!("    {}{} ({} records)", name, if header.is_master() { " [master]" } else { "" }, header.record_count);
+        if !header.author.is_empty() {
+            println!("        Author: {}", header.author);
+        }
+        if !header.description.is_empty() {
+            println!("        Description: {}", header.description.replace("\r\n", " ").replace('\n', " "));
+        }
+        if header.masters.is_empty() {
+            println!("        Masters: none");
+        } else {
+            println!("        Masters: {}", header.masters.join(", "));
+        }
+    }
+    Ok(())
+}
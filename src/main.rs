@@ -22,6 +22,23 @@ mod cache;
 mod remote;
 mod mods;
 mod file_ownership;
+mod search;
+mod info;
+mod resolver;
+mod versions;
+mod upgrade;
+mod digest;
+mod trust;
+mod transaction;
+mod owns;
+mod conflicts;
+mod deploy;
+mod plugins;
+mod tes4;
+mod masterlist;
+mod plugin_sort;
+mod record_conflicts;
+mod patch;
 
 #[macro_use]
 extern crate clap;
@@ -30,6 +47,8 @@ use std::process::exit;
 use std::env;
 use substring::Substring;
 use std::fs;
+use std::io;
+use std::io::Write;
 use std::path::Path;
 
 fn resolve_home_dir(path: &str) -> String {
@@ -44,6 +63,50 @@ fn resolve_home_dir(path: &str) -> String {
     }
 }
 
+/// Say up front which repositories can't be installed from
+fn report_unusable_repositories(config_path: &str, remotes: &[String]) {
+    for repo in remotes {
+        if let Err(issue) = remote::load_trusted_index(config_path, repo) {
+            println!("Not installing from {} <- {}", repo, issue);
+        }
+    }
+}
+
+/// Ask a yes or no question on the terminal, defaulting to no
+fn confirm(question: &str) -> bool {
+    print!("{}", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false
+    }
+}
+
+/// Undo everything a failed transaction did and quit
+fn abort_transaction(transaction: transaction::Transaction, message: &str) -> ! {
+    println!("{}", message);
+    match transaction.rollback() {
+        Ok(_) => println!("Rolled back every change."),
+        Err(issue) => println!("Failed to roll back <- {}", issue)
+    }
+    exit(1)
+}
+
+/// Print plugins whose masters are missing or load after them
+///
+/// Returns whether every master is present.
+fn report_master_problems(config_path: &str, profile: &profile::ProfileFile, mod_names: &[String]) -> Result<bool, String> {
+    let (missing, warnings) = plugins::check_masters(config_path, profile, mod_names)?;
+    for warning in &warnings {
+        println!("Warning: {}", warning);
+    }
+    for problem in &missing {
+        println!("Missing master: {}", problem);
+    }
+    Ok(missing.is_empty())
+}
+
 fn main() {
     // Load CLI arguments with clap
     let yaml = load_yaml!("arguments.yaml");
@@ -114,6 +177,43 @@ fn main() {
                         Err(issue) => { println!("Failed to remove profile <- {}", issue); 1 }
                     });
                 }
+                Some("method") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("method")
+                        .unwrap();
+                    let method = match subsubcommand_matches.value_of("method") {
+                        Some(method) => match deploy::DeployMethod::parse(method) {
+                            Ok(method) => method,
+                            Err(issue) => { println!("{}", issue); exit(1) }
+                        },
+                        None => {
+                            println!("Deployment method: {}", current_profile_file.deploy_method.name());
+                            exit(0);
+                        }
+                    };
+                    current_profile_file.deploy_method = method;
+                    let mut transaction = match transaction::Transaction::begin(&config_path) {
+                        Ok(transaction) => transaction,
+                        Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+                    };
+                    // Put every deployed file back in Data the new way
+                    match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                        Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                        Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+                    }
+                    if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                        abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+                    }
+                    if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                        abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+                    }
+                    if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                        abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+                    }
+                    exit(match transaction.commit() {
+                        Ok(_) => 0,
+                        Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+                    });
+                }
                 _ => {
                     println!("Command missing! Try with -h for more info.");
                     exit(1);
@@ -146,6 +246,44 @@ fn main() {
                 }
             }
         }
+        Some("repo") => {
+            let subcommand_matches = matches.subcommand_matches("repo")
+                .unwrap();
+            match subcommand_matches.subcommand_name() {
+                Some("ls") => {
+                    exit(match trust::list_repositories(&config_path, &config_file.repository_list) {
+                        Ok(_) => 0,
+                        Err(issue) => { println!("Failed to list repositories <- {}", issue); 1 }
+                    });
+                }
+                Some("trust") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("trust")
+                        .unwrap();
+                    let repository = subsubcommand_matches.value_of("repository")
+                        .expect("Error reading repository.");
+                    let key = subsubcommand_matches.value_of("key")
+                        .expect("Error reading key.");
+                    exit(match trust::trust_repository(&config_path, repository, key) {
+                        Ok(_) => { println!("Trusting indices of {} signed by {}", repository, key); 0 },
+                        Err(issue) => { println!("Failed to trust {} <- {}", repository, issue); 1 }
+                    });
+                }
+                Some("untrust") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("untrust")
+                        .unwrap();
+                    let repository = subsubcommand_matches.value_of("repository")
+                        .expect("Error reading repository.");
+                    exit(match trust::untrust_repository(&config_path, repository) {
+                        Ok(_) => { println!("No longer trusting {}", repository); 0 },
+                        Err(issue) => { println!("Failed to untrust {} <- {}", repository, issue); 1 }
+                    });
+                }
+                _ => {
+                    println!("Command missing! Try with -h for more info.");
+                    exit(1);
+                }
+            }
+        }
         Some("install") => {
             let subcommand_matches = matches.subcommand_matches("install")
                 .unwrap();
@@ -154,67 +292,648 @@ fn main() {
             if subcommand_matches.is_present("update") {
                 let repos = &config_file.repository_list;
                 for repo in repos {
-                    remote::get_index(&repo);
+                    match remote::get_index(&config_path, repo) {
+                        Ok(_) => { },
+                        Err(issue) => { println!("Failed to update index for {} <- {}", repo, issue); exit(1) }
+                    }
                 }
             }
-            // Create a queue of mods to install
-            let mut mod_queue = Vec::new();
+            report_unusable_repositories(&config_path, &config_file.repository_list);
             // Collect requested mods into vector
+            let mut requested_mods = Vec::new();
             for mod_value in subcommand_matches.values_of("name").unwrap() {
-                mod_queue.push(mod_value.to_string().clone());
-            }
-            // Recursively install mods
-            loop {
-                // Break out of the loop if finished
-                if mod_queue.len() == 0 {
-                    break;
+                match versions::parse_spec(mod_value) {
+                    Ok(spec) => requested_mods.push(spec),
+                    Err(issue) => { println!("Failed to parse {} <- {}", mod_value, issue); exit(1) }
                 }
-                // Get current mod from the top of the vector
-                let mod_value = mod_queue.pop().unwrap();
+            }
+            // Work out everything that has to be installed before touching the Data directory
+            let plan = match resolver::resolve(&requested_mods, &current_profile_file.installed_versions(),
+                |mod_name| resolver::repository_candidates(&config_path, &config_file.repository_list, mod_name, true)) {
+                Ok(plan) => plan,
+                Err(issue) => { println!("Failed to resolve dependencies <- {}", issue); exit(1) }
+            };
+            resolver::print_plan(&plan, &requested_mods);
+            // Explicitly requesting a mod that came in as a dependency keeps it around for good
+            current_profile_file.dependency_mods.retain(|item| !plan.already_installed.contains(item));
+            // Either every mod in the plan gets installed or none of them do
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            for mod_value in &plan.mods {
                 // Install mod
-                match mods::install_mod(&config_path, &mod_value, &verbose, &force) {
-                    Ok(_) => { println!("Installed {}", &mod_value) },
-                    Err(issue) => { println!("Failed to install {} <- {}", &mod_value, &issue); exit(1); }
+                match mods::install_mod(&config_path, mod_value, &verbose, &force) {
+                    Ok(_) => { println!("Installed {}", mod_value) },
+                    Err(issue) => abort_transaction(transaction, &format!("Failed to install {} <- {}", mod_value, &issue))
                 }
                 // Update file ownership hashmap
-                match file_ownership::installation_update(&config_path, &mod_value, &verbose) {
+                match file_ownership::installation_update(&config_path, mod_value, &verbose) {
                     Ok(_) => { },
-                    Err(issue) => { println!("Failed to update file ownership table <- {}", issue); exit(1) }
+                    Err(issue) => abort_transaction(transaction, &format!("Failed to update file ownership table <- {}", issue))
+                }
+                let mod_name = mods::mod_name(mod_value).to_string();
+                if let Some(version) = mods::mod_version(mod_value) {
+                    current_profile_file.mod_versions.insert(mod_name.clone(), version.to_string());
                 }
-                // Push dependencies to stack
-                let depends = remote::fetch_mod_depends(&config_path, &config_file.repository_list, &mod_value);
-                for item in depends {
-                    println!("{} depends on {}", &mod_value, &item);
-                    mod_queue.push(item);
+                if !requested_mods.iter().any(|spec| spec.name == mod_name) {
+                    current_profile_file.dependency_mods.push(mod_name.clone());
                 }
-                current_profile_file.enabled_mods.push(mod_value.clone());
+                current_profile_file.mod_depends.insert(mod_name.clone(), plan.depends[mod_value].clone());
+                // New mods win conflicts until they are given another priority
+                current_profile_file.mod_order.push(mod_name.clone());
+                current_profile_file.enabled_mods.push(mod_name);
+            }
+            // Build the Data directory from the staged mods
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            let installed: Vec<String> = plan.mods.iter().map(|mod_value| mods::mod_name(mod_value).to_string()).collect();
+            match report_master_problems(&config_path, &current_profile_file, &installed) {
+                Ok(true) => {},
+                Ok(false) if force => println!("Installing anyway because of --force"),
+                Ok(false) => abort_transaction(transaction, "Refusing to install plugins without their masters. Use --force to install anyway"),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to check plugin masters <- {}", issue))
             }
             // Update profile
-            exit(match profile::save_profile_file(&config_path, current_profile_file) {
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save installed mods to profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
                 Ok(_) => 0,
-                Err(issue) => { println!("Failed to save installed mods to profile <- {}", issue); 1 }
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some("search") => {
+            let subcommand_matches = matches.subcommand_matches("search")
+                .unwrap();
+            // Update repository index if requested
+            if subcommand_matches.is_present("update") {
+                for repo in &config_file.repository_list {
+                    match remote::get_index(&config_path, repo) {
+                        Ok(_) => { },
+                        Err(issue) => println!("Failed to update index for {} <- {}", repo, issue)
+                    }
+                }
+            }
+            let query: Vec<&str> = subcommand_matches.values_of("query").unwrap().collect();
+            exit(match search::search(&config_path, &config_file.repository_list, &query) {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to search repositories <- {}", issue); 1 }
+            });
+        }
+        Some("info") => {
+            let subcommand_matches = matches.subcommand_matches("info")
+                .unwrap();
+            let mod_value = subcommand_matches.value_of("name")
+                .expect("Error reading name of mod.");
+            exit(match info::show_info(&config_path, &config_file.repository_list, &current_profile_file, mod_value) {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to show info for {} <- {}", mod_value, issue); 1 }
+            });
+        }
+        Some("list") => {
+            profile::list_mods(&current_profile_file);
+        }
+        Some("conflicts") => {
+            let subcommand_matches = matches.subcommand_matches("conflicts")
+                .unwrap();
+            let mut requested_mods = Vec::new();
+            for mod_value in subcommand_matches.values_of("name").into_iter().flatten() {
+                match versions::parse_spec(mod_value) {
+                    Ok(spec) => requested_mods.push(spec),
+                    Err(issue) => { println!("Failed to parse {} <- {}", mod_value, issue); exit(1) }
+                }
+            }
+            // Check the mods exactly as install would put them in, dependencies included
+            let mut candidates = Vec::new();
+            if !requested_mods.is_empty() {
+                report_unusable_repositories(&config_path, &config_file.repository_list);
+                match resolver::resolve(&requested_mods, &current_profile_file.installed_versions(),
+                    |mod_name| resolver::repository_candidates(&config_path, &config_file.repository_list, mod_name, false)) {
+                    Ok(plan) => candidates = plan.mods,
+                    Err(issue) => { println!("Failed to resolve dependencies <- {}", issue); exit(1) }
+                }
+            }
+            exit(match conflicts::show_conflicts(&config_path, &current_profile_file, &candidates) {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to check for file conflicts <- {}", issue); 1 }
+            });
+        }
+        Some("order") => {
+            let subcommand_matches = matches.subcommand_matches("order")
+                .unwrap();
+            let (mod_value, priority) = match subcommand_matches.subcommand_name() {
+                Some("ls") => {
+                    profile::list_order(&current_profile_file);
+                    exit(0);
+                }
+                Some("move") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("move")
+                        .unwrap();
+                    let mod_value = subsubcommand_matches.value_of("name")
+                        .expect("Error reading name of mod.");
+                    match subsubcommand_matches.value_of("priority").unwrap().parse::<usize>() {
+                        Ok(priority) => (mod_value, priority),
+                        Err(_) => { println!("Priority must be a positive number"); exit(1) }
+                    }
+                }
+                Some(direction) => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches(direction)
+                        .unwrap();
+                    let mod_value = subsubcommand_matches.value_of("name")
+                        .expect("Error reading name of mod.");
+                    let steps = match subsubcommand_matches.value_of("steps").unwrap_or("1").parse::<usize>() {
+                        Ok(steps) => steps,
+                        Err(_) => { println!("Number of steps must be a positive number"); exit(1) }
+                    };
+                    let current = match current_profile_file.priority(mods::mod_name(mod_value)) {
+                        Some(current) => current,
+                        None => { println!("{} is not installed", mod_value); exit(1) }
+                    };
+                    // Up means winning more conflicts
+                    match direction {
+                        "up" => (mod_value, (current + steps).min(current_profile_file.mod_order.len())),
+                        _ => (mod_value, current.saturating_sub(steps).max(1))
+                    }
+                }
+                None => {
+                    println!("Command missing! Try with -h for more info.");
+                    exit(1);
+                }
+            };
+            if let Err(issue) = current_profile_file.set_priority(mods::mod_name(mod_value), priority) {
+                println!("Failed to change priority of {} <- {}", mod_value, issue);
+                exit(1);
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            // Swap the copies that now lose their conflicts out of Data
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, true, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            profile::list_order(&current_profile_file);
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save priorities to profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some(command @ ("enable" | "disable")) => {
+            let subcommand_matches = matches.subcommand_matches(command)
+                .unwrap();
+            let enable = command == "enable";
+            let mut enabled = Vec::new();
+            for mod_value in subcommand_matches.values_of("name").unwrap() {
+                let mod_name = mods::mod_name(mod_value).to_string();
+                if !current_profile_file.enabled_mods.contains(&mod_name) {
+                    println!("{} isn't installed!", mod_name);
+                    exit(1);
+                }
+                if enable == current_profile_file.is_enabled(&mod_name) {
+                    println!("{} is already {}d", mod_name, command);
+                    continue;
+                }
+                if enable {
+                    current_profile_file.disabled_mods.retain(|item| *item != mod_name);
+                    enabled.push(mod_name.clone());
+                } else {
+                    // Dependents stay enabled, so let the user know what may break
+                    for dependent in current_profile_file.reverse_depends(&mod_name) {
+                        if current_profile_file.is_enabled(&dependent) {
+                            println!("Warning: {} depends on {}", dependent, mod_name);
+                        }
+                    }
+                    current_profile_file.disabled_mods.push(mod_name.clone());
+                }
+                println!("{} {}", if enable { "Enabled" } else { "Disabled" }, current_profile_file.installed_value(&mod_name));
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            match report_master_problems(&config_path, &current_profile_file, &enabled) {
+                Ok(true) => {},
+                Ok(false) if subcommand_matches.is_present("force") => println!("Enabling anyway because of --force"),
+                Ok(false) => abort_transaction(transaction, "Refusing to enable plugins without their masters. Use --force to enable anyway"),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to check plugin masters <- {}", issue))
+            }
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some("deploy") => {
+            let subcommand_matches = matches.subcommand_matches("deploy")
+                .unwrap();
+            if subcommand_matches.is_present("dry-run") {
+                exit(match deploy::preview_deployment(&config_path, &current_profile_file) {
+                    Ok(operations) => { deploy::print_summary(&operations, verbose, true); 0 }
+                    Err(issue) => { println!("Failed to plan deployment <- {}", issue); 1 }
+                });
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some("purge") => {
+            let subcommand_matches = matches.subcommand_matches("purge")
+                .unwrap();
+            if subcommand_matches.is_present("dry-run") {
+                exit(match deploy::preview_purge(&config_path) {
+                    Ok(operations) => { deploy::print_summary(&operations, verbose, true); 0 }
+                    Err(issue) => { println!("Failed to plan purge <- {}", issue); 1 }
+                });
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            match deploy::purge(&config_path, &current_profile_file.install_path, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to purge managed files <- {}", issue))
+            }
+            // Plugins of purged mods stay in the load order but leave plugins.txt
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some("plugins") => {
+            let subcommand_matches = matches.subcommand_matches("plugins")
+                .unwrap();
+            if let Err(issue) = plugins::sync_load_order(&config_path, &mut current_profile_file) {
+                println!("Failed to read load order <- {}", issue);
+                exit(1);
+            }
+            let result = match subcommand_matches.subcommand_name() {
+                Some("ls") => {
+                    exit(match plugins::list_plugins(&config_path, &current_profile_file) {
+                        Ok(_) => 0,
+                        Err(issue) => { println!("Failed to list plugins <- {}", issue); 1 }
+                    });
+                }
+                Some(command @ ("enable" | "disable")) => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches(command)
+                        .unwrap();
+                    let names: Vec<&str> = subsubcommand_matches.values_of("plugin").unwrap().collect();
+                    plugins::set_active(&mut current_profile_file, &names, command == "enable")
+                }
+                Some("conflicts") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("conflicts")
+                        .unwrap();
+                    exit(match record_conflicts::show_record_conflicts(&current_profile_file, subsubcommand_matches.value_of("plugin"), subsubcommand_matches.is_present("all")) {
+                        Ok(_) => 0,
+                        Err(issue) => { println!("Failed to check for record conflicts <- {}", issue); 1 }
+                    });
+                }
+                Some("rules") => {
+                    plugins::list_rules(&current_profile_file);
+                    exit(0);
+                }
+                Some("dir") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("dir")
+                        .unwrap();
+                    match subsubcommand_matches.value_of("directory") {
+                        None if !subsubcommand_matches.is_present("reset") => {
+                            exit(match plugins::plugins_directory(&config_path, &current_profile_file) {
+                                Ok(directory) => { println!("Load order is written to {}", directory); 0 }
+                                Err(issue) => { println!("Failed to get profile directory <- {}", issue); 1 }
+                            });
+                        }
+                        directory => plugins::set_plugins_directory(&mut current_profile_file, directory)
+                    }
+                }
+                Some("masterlist") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("masterlist")
+                        .unwrap();
+                    match subsubcommand_matches.value_of("path") {
+                        None if !subsubcommand_matches.is_present("reset") => {
+                            match current_profile_file.masterlist.is_empty() {
+                                true => println!("No masterlist is set"),
+                                false => println!("Masterlist: {}", current_profile_file.masterlist)
+                            }
+                            exit(0);
+                        }
+                        path => plugin_sort::set_masterlist(&mut current_profile_file, path)
+                    }
+                }
+                Some("after") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("after")
+                        .unwrap();
+                    let plugin = subsubcommand_matches.value_of("plugin")
+                        .expect("Error reading name of plugin.");
+                    let afters: Vec<&str> = subsubcommand_matches.values_of("after").unwrap().collect();
+                    if subsubcommand_matches.is_present("remove") {
+                        plugins::remove_rules(&mut current_profile_file, plugin, &afters)
+                    } else {
+                        plugins::add_rules(&mut current_profile_file, plugin, &afters);
+                        Ok(())
+                    }
+                }
+                Some("sort") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("sort")
+                        .unwrap();
+                    let masterlist_path = match subsubcommand_matches.value_of("masterlist") {
+                        Some(path) => Some(path.to_string()),
+                        None if !current_profile_file.masterlist.is_empty() => Some(current_profile_file.masterlist.clone()),
+                        None => None
+                    };
+                    let (sorted, notes) = match plugin_sort::sort_plugins(&current_profile_file, masterlist_path.as_deref()) {
+                        Ok(sorted) => sorted,
+                        Err(issue) => { println!("Failed to sort plugins <- {}", issue); exit(1) }
+                    };
+                    for note in &notes {
+                        println!("{}", note);
+                    }
+                    if sorted == current_profile_file.load_order {
+                        println!("Load order is already sorted.");
+                    }
+                    if subsubcommand_matches.is_present("dry-run") {
+                        exit(0);
+                    }
+                    current_profile_file.load_order = sorted;
+                    Ok(())
+                }
+                Some("move") => {
+                    let subsubcommand_matches = subcommand_matches.subcommand_matches("move")
+                        .unwrap();
+                    let name = subsubcommand_matches.value_of("plugin")
+                        .expect("Error reading name of plugin.");
+                    match subsubcommand_matches.value_of("position").unwrap().parse::<usize>() {
+                        Ok(position) => plugins::move_plugin(&mut current_profile_file, name, position),
+                        Err(_) => { println!("Position must be a positive number"); exit(1) }
+                    }
+                }
+                _ => {
+                    println!("Command missing! Try with -h for more info.");
+                    exit(1);
+                }
+            };
+            if let Err(issue) = result {
+                println!("Failed to change load order <- {}", issue);
+                exit(1);
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            if let Err(issue) = plugins::write_load_order(&config_path, &current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to write load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save load order to profile <- {}", issue));
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
+        }
+        Some("patch") => {
+            let subcommand_matches = matches.subcommand_matches("patch")
+                .unwrap();
+            match subcommand_matches.subcommand_name() {
+                Some("build") => {
+                    if let Err(issue) = plugins::sync_load_order(&config_path, &mut current_profile_file) {
+                        println!("Failed to read load order <- {}", issue);
+                        exit(1);
+                    }
+                    let mut transaction = match transaction::Transaction::begin(&config_path) {
+                        Ok(transaction) => transaction,
+                        Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+                    };
+                    match patch::build_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                        Ok(merged) => println!("Merged {} leveled list{} into {}", merged, if merged == 1 { "" } else { "s" }, patch::PATCH_PLUGIN),
+                        Err(issue) => abort_transaction(transaction, &format!("Failed to build merged patch <- {}", issue))
+                    }
+                    if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                        abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+                    }
+                    exit(match transaction.commit() {
+                        Ok(_) => 0,
+                        Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+                    });
+                }
+                _ => {
+                    println!("Command missing! Try with -h for more info.");
+                    exit(1);
+                }
+            }
+        }
+        Some("owns") => {
+            let subcommand_matches = matches.subcommand_matches("owns")
+                .unwrap();
+            let paths: Vec<&str> = subcommand_matches.values_of("path").unwrap().collect();
+            exit(match owns::show_owners(&config_path, &current_profile_file.install_path, &paths) {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(issue) => { println!("Failed to look up file owners <- {}", issue); 1 }
+            });
+        }
+        Some("upgrade") => {
+            let subcommand_matches = matches.subcommand_matches("upgrade")
+                .unwrap();
+            let force = subcommand_matches.is_present("force");
+            // Update repository index if requested
+            if subcommand_matches.is_present("update") {
+                for repo in &config_file.repository_list {
+                    match remote::get_index(&config_path, repo) {
+                        Ok(_) => { },
+                        Err(issue) => { println!("Failed to update index for {} <- {}", repo, issue); exit(1) }
+                    }
+                }
+            }
+            let mut requested_mods = Vec::new();
+            for mod_value in subcommand_matches.values_of("name").into_iter().flatten() {
+                match versions::parse_spec(mod_value) {
+                    Ok(spec) => requested_mods.push(spec),
+                    Err(issue) => { println!("Failed to parse {} <- {}", mod_value, issue); exit(1) }
+                }
+            }
+            report_unusable_repositories(&config_path, &config_file.repository_list);
+            let found = match subcommand_matches.is_present("previous") {
+                true => upgrade::find_previous_builds(&config_path, &current_profile_file, &requested_mods),
+                false => upgrade::find_upgrades(&config_path, &config_file.repository_list, &current_profile_file, &requested_mods)
+            };
+            let mut upgrades = match found {
+                Ok(upgrades) => upgrades,
+                Err(issue) => { println!("Failed to check for upgrades <- {}", issue); exit(1) }
+            };
+            for reason in upgrade::check_upgrades(&current_profile_file, &mut upgrades) {
+                println!("{}", reason);
+            }
+            upgrade::print_upgrades(&upgrades);
+            if subcommand_matches.is_present("list") {
+                exit(0);
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            for item in &upgrades {
+                match mods::upgrade_mod(&config_path, &item.old_value, &item.new_value, &item.previous, &force, &mut transaction) {
+                    Ok(_) if item.previous => println!("Rolled {} back to its previous build", item.old_value),
+                    Ok(_) => { println!("Upgraded {} to {}", item.old_value, item.new_value) },
+                    Err(issue) => abort_transaction(transaction, &format!("Failed to upgrade {} <- {}", item.old_value, issue))
+                }
+                let mod_name = mods::mod_name(&item.new_value).to_string();
+                match mods::mod_version(&item.new_value) {
+                    Some(version) => current_profile_file.mod_versions.insert(mod_name.clone(), version.to_string()),
+                    None => current_profile_file.mod_versions.remove(&mod_name)
+                };
+                current_profile_file.mod_depends.insert(mod_name.clone(), item.depends.clone());
+                if item.rebuild && !item.previous {
+                    println!("The previous build of {} is still cached. Roll back with: pipboy upgrade --previous {}", item.old_value, mod_name);
+                } else if mods::mod_version(&item.old_value).is_some() && !item.rebuild {
+                    println!("{} is still cached. Roll back with: pipboy upgrade {}", item.old_value, item.old_value);
+                }
+            }
+            // Build the Data directory from the staged mods
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
+            }
+            // Update profile
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save upgraded mods to profile <- {}", issue));
+            }
+            // Data no longer uses the staged copies of the versions that were replaced
+            for item in upgrades.iter().filter(|item| item.old_value != item.new_value) {
+                if let Err(issue) = deploy::discard_staged(&config_path, &item.old_value, &mut transaction) {
+                    abort_transaction(transaction, &format!("Failed to remove staged copy of {} <- {}", item.old_value, issue));
+                }
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
             });
         }
         Some("uninstall") => {
             let subcommand_matches = matches.subcommand_matches("uninstall")
                 .unwrap();
-            for mod_value in subcommand_matches.values_of("name").unwrap() {
+            let requested_mods: Vec<String> = subcommand_matches.values_of("name").unwrap()
+                .map(|mod_value| mods::mod_name(mod_value).to_string())
+                .collect();
+            let mut removing = match resolver::plan_removal(&current_profile_file, &requested_mods, subcommand_matches.is_present("cascade")) {
+                Ok(removing) => removing,
+                Err(issue) => { println!("Failed to uninstall <- {}", issue); exit(1) }
+            };
+            // Offer to clean up dependencies nothing needs anymore
+            let orphans = resolver::find_orphans(&current_profile_file, &removing);
+            if !orphans.is_empty() {
+                println!("These dependencies would no longer be needed: {}", orphans.join(", "));
+                if subcommand_matches.is_present("yes") || confirm("Remove them too? [y/N] ") {
+                    removing.extend(orphans);
+                }
+            }
+            let mut transaction = match transaction::Transaction::begin(&config_path) {
+                Ok(transaction) => transaction,
+                Err(issue) => { println!("Failed to begin transaction <- {}", issue); exit(1) }
+            };
+            let mut uninstalled = Vec::new();
+            for mod_name in resolver::order_removal(&current_profile_file, &removing) {
+                let mod_value = current_profile_file.installed_value(&mod_name);
                 // Remove the mod
                 match mods::uninstall_mod(&config_path, &mod_value) {
                     Ok(_) => { println!("Uninstalled {}", &mod_value) },
-                    Err(issue) => {
-                        println!("Failed to uninstall {} <- {}", &mod_value, issue);
-                        exit(1)
-                    }
+                    Err(issue) => abort_transaction(transaction, &format!("Failed to uninstall {} <- {}", &mod_value, issue))
                 }
-                // Update file ownership dictionary
-                match file_ownership::uninstallation_update(&config_path, &mod_value) {
-                    Ok(_) => { println!("Updated file ownership table.") },
-                    Err(issue) => { println!("Failed to update file ownership table <- {}", issue); exit(1) }
-                };
                 // Remove mod from profile vector
-                
+                current_profile_file.remove_mod(&mod_name);
+                uninstalled.push(mod_value);
+            }
+            // Build the Data directory from the staged mods
+            match deploy::deploy(&config_path, &current_profile_file, &mut transaction) {
+                Ok(operations) => deploy::print_summary(&operations, verbose, false),
+                Err(issue) => abort_transaction(transaction, &format!("Failed to deploy mods <- {}", issue))
+            }
+            if let Err(issue) = plugins::update_load_order(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to update load order <- {}", issue));
+            }
+            if let Err(issue) = patch::refresh_patch(&config_path, &mut current_profile_file, &mut transaction) {
+                abort_transaction(transaction, &format!("Failed to rebuild merged patch <- {}", issue));
             }
+            // Update profile
+            if let Err(issue) = profile::save_profile_file(&config_path, current_profile_file) {
+                abort_transaction(transaction, &format!("Failed to save profile <- {}", issue));
+            }
+            // Data no longer uses the staged copies of the uninstalled mods
+            for mod_value in &uninstalled {
+                if let Err(issue) = deploy::discard_staged(&config_path, mod_value, &mut transaction) {
+                    abort_transaction(transaction, &format!("Failed to remove staged copy of {} <- {}", mod_value, issue));
+                }
+            }
+            exit(match transaction.commit() {
+                Ok(_) => 0,
+                Err(issue) => { println!("Failed to finish transaction <- {}", issue); 1 }
+            });
         }
         _ => {
             println!("Command missing! Try with -h for more info.");
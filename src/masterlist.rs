@@ -0,0 +1,65 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// LOOT treats plugin names containing any of these as regular expressions
+const REGEX_CHARACTERS: [char; 4] = [':', '\\', '*', '?'];
+
+/// The plugin a file entry such as those under `after` refers to. Entries are either a
+/// plain name or a map with a `name` key.
+fn entry_name(entry: &Yaml) -> Option<&str> {
+    match entry {
+        Yaml::String(name) => Some(name),
+        Yaml::Hash(_) => entry["name"].as_str(),
+        _ => None
+    }
+}
+
+/// Read the load-after rules of a LOOT masterlist or userlist as (plugin, loads after) pairs
+///
+/// Only `after` entries are used. Plugins named by regular expression are skipped.
+pub(crate) fn load_rules(path: &str) -> Result<Vec<(String, String)>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(issue) => return Err(format!("Failed to read {} <- {}", path, issue))
+    };
+    let documents = match YamlLoader::load_from_str(&contents) {
+        Ok(documents) => documents,
+        Err(issue) => return Err(format!("Failed to parse {} <- {}", path, issue))
+    };
+    let mut rules = Vec::new();
+    for document in &documents {
+        let plugins = match document["plugins"].as_vec() {
+            Some(plugins) => plugins,
+            None => continue
+        };
+        for plugin in plugins {
+            let name = match plugin["name"].as_str() {
+                Some(name) if !name.contains(&REGEX_CHARACTERS[..]) => name,
+                _ => continue
+            };
+            for after in plugin["after"].as_vec().into_iter().flatten() {
+                if let Some(after) = entry_name(after) {
+                    rules.push((name.to_string(), after.to_string()));
+                }
+            }
+        }
+    }
+    Ok(rules)
+}
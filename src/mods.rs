@@ -15,6 +15,7 @@
 // You should have received a copy of the GNU General Public License
 // along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashSet;
 use std::path::Path;
 use std::fs;
 use std::fs::File;
@@ -24,19 +25,13 @@ use substring::Substring;
 use super::archives;
 use super::profile;
 use super::config_file;
+use super::digest;
+use super::file_ownership;
+use super::deploy;
 use super::remote;
+use super::transaction::Transaction;
 
 pub(crate) fn install_mod(config_path: &str, mod_value: &str, verbose: &bool, force: &bool) -> Result<(), String> {
-    // Get current profile
-    let profile_name = match config_file::current_profile(&config_path) {
-        Ok(value) => value,
-        Err(issue) => return Err(format!("Failed to get current profile <- {}", issue))
-    };
-    // Load profile file
-    let profile = match profile::load_profile_file(&format!("{}/profiles/{}/profile", &config_path, &profile_name)) {
-        Ok(value) => value,
-        Err(_) => return Err("Failed to load profile file".to_string())
-    };
     // Test if mod is already installed
     match mod_is_installed(&config_path, &mod_value) {
         Ok(result) => match result {
@@ -77,22 +72,18 @@ pub(crate) fn install_mod(config_path: &str, mod_value: &str, verbose: &bool, fo
         },
         true => println!("Force flag given. Skipping testing for file conflicts.")
     };
-    // Install the mod
-    let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", &config_path, &mod_value);
-    return match archives::unpack_tarball(&tarball_path, &profile.install_path) {
+    // Extract the mod so it can be deployed
+    match deploy::stage_mod(config_path, mod_value) {
         Ok(_) => Ok(()),
-        Err(_) => Err("Failed to extract tarball!".to_string())
-    };
+        Err(issue) => Err(format!("Failed to stage {} <- {}", mod_value, issue))
+    }
 }
 
 pub(crate) fn generate_index(config_path: &str, mod_value: &str, verbose: &bool) -> Result<(), String> {
-    let mod_values = split_mod_value(mod_value);
-    let mod_author = &mod_values[0];
-    let mod_name = &mod_values[1];
-    println!("{}", format!("Generating file index for {}/{}", &mod_author, &mod_name));
+    println!("Generating file index for {}", mod_value);
     // Create mod path
-    let mod_path = format!("{}/mods/cached/{}/{}/mod.tar.gz", &config_path, &mod_author, &mod_name);
-    let index_path = format!("{}/mods/indices/{}/{}/index", &config_path, &mod_author, &mod_name);
+    let mod_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mod_directory(mod_value));
+    let index_path = format!("{}/mods/indices/{}/index", config_path, mod_directory(mod_value));
     // Create indices path if it doesn't exist
     if !Path::new(&index_path).parent().unwrap().exists() {
         fs::create_dir_all(Path::new(&index_path).parent().unwrap()).expect("Unable to create indices folder. Ensure you have permission to do this.");
@@ -121,20 +112,29 @@ pub(crate) fn generate_index(config_path: &str, mod_value: &str, verbose: &bool)
 }
 
 pub(crate) fn mod_has_index(config_path: &str, mod_value: &str) -> bool {
-    let mod_values = split_mod_value(mod_value);
-    let mod_author = &mod_values[0];
-    let mod_name = &mod_values[1];
-    let index_path = format!("{}/mods/indices/{}/{}/index", &config_path, &mod_author, &mod_name);
-    return Path::new(&index_path).exists();
+    let index_path = format!("{}/mods/indices/{}/index", config_path, mod_directory(mod_value));
+    Path::new(&index_path).exists()
 }
 
-pub(crate) fn split_mod_value(mod_value: &str) -> Vec<String> {
-    // I should be collecting this iterator but I don't know how
-    let mut vec = Vec::new();
-    for part in mod_value.split("/") {
-        vec.push(part.to_string());
+/// The `author/mod` part of a mod value, without any `@version`
+pub(crate) fn mod_name(mod_value: &str) -> &str {
+    match mod_value.find('@') {
+        Some(position) => &mod_value[..position],
+        None => mod_value
+    }
+}
+
+/// The version part of a mod value formatted `author/mod@version`
+pub(crate) fn mod_version(mod_value: &str) -> Option<&str> {
+    mod_value.find('@').map(|position| &mod_value[position + 1..])
+}
+
+/// The relative directory a mod is stored under, `author/mod/version` or `author/mod`
+pub(crate) fn mod_directory(mod_value: &str) -> String {
+    match mod_version(mod_value) {
+        Some(version) => format!("{}/{}", mod_name(mod_value), version),
+        None => mod_value.to_string()
     }
-    return vec;
 }
 
 pub(crate) fn mod_is_cached(config_path: &str, mod_value: &str) -> Result<bool, String> {
@@ -148,18 +148,40 @@ pub(crate) fn mod_is_cached(config_path: &str, mod_value: &str) -> Result<bool,
         };
     }
     // Search the mod cache for a mod
-    let mod_path: &str = &format!("{}/{}/mod.tar.gz", &mod_cache_path, &mod_value);
-    // Return the value
-    if Path::new(&mod_path).exists() {
-        Ok(true)
-    } else {
-        Ok(false)
+    let mod_path: &str = &format!("{}/{}/mod.tar.gz", &mod_cache_path, mod_directory(mod_value));
+    if !Path::new(&mod_path).exists() {
+        return Ok(false);
     }
+    // Make sure the cached copy is still the one that was verified when it was downloaded.
+    // A cached build that no longer matches the repository is a rebuild for upgrade to pick
+    // up rather than corruption, so the repository index isn't consulted here.
+    let digest_path = cached_digest_path(mod_path);
+    if Path::new(&digest_path).exists() {
+        let expected = match fs::read_to_string(&digest_path) {
+            Ok(expected) => expected.trim().to_lowercase(),
+            Err(_) => return Err(format!("Failed to read {}", digest_path))
+        };
+        if digest::sha256_file(mod_path)? != expected {
+            println!("Cached copy of {} is corrupt and will be downloaded again", mod_value);
+            for path in [mod_path, &digest_path] {
+                if fs::remove_file(path).is_err() {
+                    return Err(format!("Failed to remove corrupt file {}", path));
+                }
+            }
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Where the digest a cached tarball was verified against when it was downloaded is kept
+pub(crate) fn cached_digest_path(tarball_path: &str) -> String {
+    format!("{}.sha256", tarball_path)
 }
 
 pub(crate) fn test_file_conflicts(config_path: &str, mod_value: &str, verbose: &bool) -> Result<bool, String> {
     // Get mod index path
-    let index_path = format!("{}/mods/indices/{}/index", &config_path, &mod_value);
+    let index_path = format!("{}/mods/indices/{}/index", config_path, mod_directory(mod_value));
     // Get Data path
     let data_path = match config_file::load_config_file(&config_path) {
         Ok(config) => match profile::load_profile_file(&format!("{}/profiles/{}/profile", &config_path, &config.current_profile)) {
@@ -182,12 +204,14 @@ pub(crate) fn test_file_conflicts(config_path: &str, mod_value: &str, verbose: &
     // Load mod index file
     let files: String = fs::read_to_string(&index_path)
         .unwrap().parse().unwrap();
+    let table = file_ownership::load_ownership_table(config_path)?;
     // Iterate over mod files and see if they would conflict with another file
     for item in files.lines() {
         // Only test files that are going into the Data/ path
         if item.substring(0, 5) == "Data/" && item != "Data/" {
             let outpath = format!("{}/{}", &data_path, &item);
-            if Path::new(&outpath).exists() {
+            // Files of other installed mods may not have been deployed yet
+            if Path::new(&outpath).exists() || !table.providers(item).is_empty() {
                 println!("File conflict: {}", &item);
                 return Ok(true);
             } else {
@@ -208,14 +232,14 @@ pub(crate) fn mod_is_installed(config_path: &str, mod_value: &str) -> Result<boo
     };
     let profile_path = format!("{}/profiles/{}/profile", &config_path, &current_profile);
     return match profile::load_profile_file(&profile_path) {
-        Ok(profile) => Ok(profile.enabled_mods.contains(&mod_value.to_string())),
+        Ok(profile) => Ok(profile.enabled_mods.iter().any(|item| item == mod_name(mod_value))),
         Err(issue) => Err(format!("Failled to load profile file <- {}", issue))
     };
 }
 
 pub(crate) fn load_index(config_path: &str, mod_value: &str) -> Result<String, String> {
     // Define mod index path
-    let index_path = format!("{}/mods/indices/{}/index", &config_path, &mod_value);
+    let index_path = format!("{}/mods/indices/{}/index", config_path, mod_directory(mod_value));
     // Create index if it doesn't exist
     if !Path::new(&index_path).exists() {
         match generate_index(&config_path, &mod_value, &false) {
@@ -233,6 +257,7 @@ pub(crate) fn load_index(config_path: &str, mod_value: &str) -> Result<String, S
         };
 }
 
+/// Take a mod out of the ownership table. Its files leave Data with the next deployment.
 pub(crate) fn uninstall_mod(config_path: &str, mod_value: &str) -> Result<(), String> {
     // Make sure the mod is installed first
     match mod_is_installed(&config_path, &mod_value) {
@@ -242,29 +267,115 @@ pub(crate) fn uninstall_mod(config_path: &str, mod_value: &str) -> Result<(), St
         },
         Err(issue) => return Err(format!("Failed to test if {} is installed <- {}", &mod_value, issue))
     };
-    // Get index of files to remove
-    let mod_index = match load_index(&config_path, &mod_value) {
-        Ok(index) => index,
-        Err(issue) => return Err(format!("Failed to load index file for {} <- {}", &mod_value, issue))
-    };
-    let current_profile = match config_file::current_profile(&config_path) {
-        Ok(profile) => profile,
-        Err(issue) => return Err(format!("Failed to get current profile <- {}", issue))
-    };
-    let install_path = match profile::load_profile_file(
-        &format!("{}/profiles/{}/profile", &config_path, &current_profile)) {
-            Ok(profile) => profile.install_path,
-            Err(issue) => return Err(format!("Failed to load profile <- {}", issue))
+    // Update file ownership dictionary
+    match file_ownership::uninstallation_update(config_path, mod_value) {
+        Ok(_) => Ok(()),
+        Err(issue) => Err(format!("Failed to update file ownership table <- {}", issue))
+    }
+}
+
+/// Where the build of a mod that a repository rebuild replaced is kept
+pub(crate) fn previous_build_path(config_path: &str, mod_value: &str) -> String {
+    format!("{}/mods/cached/{}/mod.tar.gz.previous", config_path, mod_directory(mod_value))
+}
+
+/// Hand the files of one version of a mod over to another in the ownership table
+///
+/// Files that the new version no longer ships leave Data with the next deployment. The tarball of the old version is
+/// left in the mod cache so the upgrade can be reversed, as `mod.tar.gz.previous` when the
+/// repository rebuilt the same version. With `previous`, that build is put back instead.
+pub(crate) fn upgrade_mod(config_path: &str, old_value: &str, new_value: &str, previous: &bool, force: &bool, transaction: &mut Transaction) -> Result<(), String> {
+    let mod_name = mod_name(new_value);
+    let old_index = load_index(config_path, old_value)?;
+    // A rebuild of the same version replaces the cached tarball, so set the old one aside
+    if old_value == new_value {
+        let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mod_directory(old_value));
+        let previous_path = previous_build_path(config_path, old_value);
+        let index_path = format!("{}/mods/indices/{}/index", config_path, mod_directory(old_value));
+        let tarball_digest = cached_digest_path(&tarball_path);
+        let previous_digest = cached_digest_path(&previous_path);
+        for path in [&tarball_path, &previous_path, &tarball_digest, &previous_digest, &index_path] {
+            transaction.stage_path(path)?;
+        }
+        transaction.discard_on_rollback(&deploy::staging_path(config_path, old_value))?;
+        // Each build's digest moves along with it
+        let (from, to) = match *previous {
+            true => ([&previous_path, &previous_digest], [&tarball_path, &tarball_digest]),
+            false => ([&tarball_path, &tarball_digest], [&previous_path, &previous_digest])
         };
-    // Iterate through and remove files
-    for file in mod_index.lines() {
-        let full_path = format!("{}/{}", &install_path, &file);
-        if Path::new(&full_path).exists() {
-            match fs::remove_file(&full_path) {
-                Ok(_) => {},
-                Err(_) => return Err(format!("Failed to remove file {}", &full_path))
+        for (from, to) in from.iter().zip(to.iter()) {
+            let moved = match Path::new(from).exists() {
+                true => fs::rename(from, to),
+                false if Path::new(to).exists() => fs::remove_file(to),
+                false => Ok(())
             };
+            if moved.is_err() {
+                return Err(format!("Failed to swap builds of {}", old_value));
+            }
+        }
+        if fs::remove_file(&index_path).is_err() {
+            return Err(format!("Failed to remove stale index {}", index_path));
         }
+        deploy::unstage_mod(config_path, old_value)?;
     }
-    Ok(())
-}
\ No newline at end of file
+    // Make sure the new version is available locally
+    if !mod_is_cached(config_path, new_value)? {
+        match remote::fetch_mod(config_path, new_value) {
+            Ok(_) => println!("Downloaded {} from remote server", new_value),
+            Err(issue) => return Err(format!("Failed to fetch mod from remote server <- {}", issue))
+        }
+    }
+    let new_index = load_index(config_path, new_value)?;
+    let install_path = match config_file::load_config_file(config_path) {
+        Ok(config) => match profile::load_profile_file(&format!("{}/profiles/{}/profile", config_path, config.current_profile)) {
+            Ok(profile) => profile.install_path,
+            Err(issue) => return Err(format!("Failed to load profile <- {}", issue))
+        },
+        Err(_) => return Err("Failed to load configuration file!".to_string())
+    };
+    let table = file_ownership::load_ownership_table(config_path)?;
+    let old_files: HashSet<&str> = old_index.lines().collect();
+    let new_files: HashSet<&str> = new_index.lines().collect();
+    // Files that are new in this version must not clobber files belonging to something else
+    if !*force {
+        for file in &new_files {
+            let owned = file_ownership::current_owner(&table, file) == Some(mod_name);
+            let shipped = table.providers(file).iter().any(|provider| provider.owner != mod_name);
+            let on_disk = Path::new(&format!("{}/{}", install_path, file)).exists();
+            if !old_files.contains(file) && (shipped || (on_disk && !owned)) {
+                println!("File conflict: {}", file);
+                return Err("File conflict detected!".to_string());
+            }
+        }
+    }
+    deploy::stage_mod(config_path, new_value)?;
+    // Hand the old version's files over to the new version
+    let old_files: Vec<&str> = old_index.lines().collect();
+    let new_files: Vec<&str> = new_index.lines().collect();
+    match file_ownership::upgrade_update(config_path, &old_files, new_value, &new_files) {
+        Ok(_) => Ok(()),
+        Err(issue) => Err(format!("Failed to update file ownership table <- {}", issue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_cached_tarballs_are_thrown_away() {
+        let config_path = std::env::temp_dir().join(format!("pipboy-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config_path);
+        let config_path = config_path.to_string_lossy().to_string();
+        let tarball_path = format!("{}/mods/cached/someone/mod/1.0.0/mod.tar.gz", config_path);
+        fs::create_dir_all(Path::new(&tarball_path).parent().unwrap()).unwrap();
+        fs::write(&tarball_path, "verified").unwrap();
+        fs::write(cached_digest_path(&tarball_path), format!("{}\n", digest::sha256_bytes(b"verified"))).unwrap();
+        assert!(mod_is_cached(&config_path, "someone/mod@1.0.0").unwrap());
+        fs::write(&tarball_path, "verif").unwrap();
+        assert!(!mod_is_cached(&config_path, "someone/mod@1.0.0").unwrap());
+        assert!(!Path::new(&tarball_path).exists());
+        assert!(!Path::new(&cached_digest_path(&tarball_path)).exists());
+        fs::remove_dir_all(&config_path).unwrap();
+    }
+}
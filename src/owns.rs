@@ -0,0 +1,176 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use super::file_ownership;
+use super::file_ownership::{OwnershipTable, Provider};
+
+/// Whether a path matches a glob pattern
+///
+/// `*` and `?` stay within one path component, `**` spans any number of them.
+pub(crate) fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    glob_matches_from(&pattern, &path)
+}
+
+fn glob_matches_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            // Let "**/" also match no directories at all
+            let rest = if pattern.get(2) == Some(&'/') { &pattern[3..] } else { &pattern[2..] };
+            (0..=path.len()).any(|skip| glob_matches_from(rest, &path[skip..]))
+        }
+        Some('*') => {
+            let component = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=component).any(|skip| glob_matches_from(&pattern[1..], &path[skip..]))
+        }
+        Some('?') => match path.first() {
+            Some(c) if *c != '/' => glob_matches_from(&pattern[1..], &path[1..]),
+            _ => false
+        },
+        Some(c) => path.first() == Some(c) && glob_matches_from(&pattern[1..], &path[1..])
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
+
+/// Every file under a directory, relative to `install_path`
+fn files_on_disk(install_path: &str, relative: &str, files: &mut BTreeSet<String>) {
+    let entries = match fs::read_dir(format!("{}/{}", install_path, relative)) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if relative.is_empty() { name } else { format!("{}/{}", relative, name) };
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => files_on_disk(install_path, &path, files),
+            Ok(_) => { files.insert(path); },
+            Err(_) => {}
+        }
+    }
+}
+
+/// Turn a path given on the command line into one relative to the game directory
+fn relative_path(install_path: &str, path: &str) -> String {
+    let install_path = install_path.trim_end_matches('/');
+    let path = match path.strip_prefix(install_path) {
+        Some(inside) if inside.starts_with('/') => inside,
+        _ => path
+    };
+    path.trim_start_matches("./").trim_start_matches('/').to_string()
+}
+
+/// Paths matching a pattern, both in the ownership table and on disk
+fn matching_paths(table: &OwnershipTable, install_path: &str, pattern: &str) -> BTreeSet<String> {
+    let mut matches = BTreeSet::new();
+    if !is_glob(pattern) {
+        // Paths inside the mod archives start at Data/, so allow leaving it out
+        let candidates = [pattern.to_string(), format!("Data/{}", pattern)];
+        for candidate in candidates.iter() {
+            if !table.providers(candidate).is_empty() || Path::new(&format!("{}/{}", install_path, candidate)).is_file() {
+                matches.insert(candidate.clone());
+                break;
+            }
+        }
+        return matches;
+    }
+    matches.extend(table.files.keys().filter(|file| glob_matches(pattern, file)).cloned());
+    // Only walk the directories the pattern can reach
+    let literal: Vec<&str> = pattern.split('/').take_while(|component| !is_glob(component)).collect();
+    let mut on_disk = BTreeSet::new();
+    files_on_disk(install_path, &literal.join("/"), &mut on_disk);
+    matches.extend(on_disk.into_iter().filter(|file| glob_matches(pattern, file)));
+    matches
+}
+
+/// Print which mod put each matching file into the game directory
+///
+/// Returns whether every pattern matched at least one file.
+pub(crate) fn show_owners(config_path: &str, install_path: &str, patterns: &[&str]) -> Result<bool, String> {
+    let table = match file_ownership::load_ownership_table(config_path) {
+        Ok(table) => table,
+        Err(issue) => return Err(format!("Failed to load file ownership table <- {}", issue))
+    };
+    let mut all_found = true;
+    for pattern in patterns {
+        let pattern = relative_path(install_path, pattern);
+        let matches = matching_paths(&table, install_path, &pattern);
+        if matches.is_empty() {
+            println!("{}: no such file", pattern);
+            all_found = false;
+            continue;
+        }
+        for file in matches {
+            let on_disk = Path::new(&format!("{}/{}", install_path, file)).is_file();
+            let providers = table.providers(&file);
+            match providers.split_last() {
+                Some((top, others)) => {
+                    let mut line = format!("{}: {}", file, top.mod_value());
+                    if !others.is_empty() {
+                        let others: Vec<String> = others.iter().rev().map(Provider::mod_value).collect();
+                        line.push_str(&format!(" (also shipped by {})", others.join(", ")));
+                    }
+                    if !on_disk {
+                        line.push_str(" [missing from disk]");
+                    }
+                    println!("{}", line);
+                }
+                None => println!("{}: unmanaged", file)
+            }
+        }
+    }
+    Ok(all_found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_patterns_match_exactly() {
+        assert!(glob_matches("Data/a.esp", "Data/a.esp"));
+        assert!(!glob_matches("Data/a.esp", "Data/a.esm"));
+        assert!(!glob_matches("Data/a.esp", "Data/a.esp.bak"));
+    }
+
+    #[test]
+    fn single_stars_and_question_marks_stay_within_a_directory() {
+        assert!(glob_matches("Data/*.esp", "Data/a.esp"));
+        assert!(glob_matches("Data/*.esp", "Data/.esp"));
+        assert!(!glob_matches("Data/*.esp", "Data/sub/a.esp"));
+        assert!(glob_matches("Data/?.esp", "Data/a.esp"));
+        assert!(!glob_matches("Data/?.esp", "Data/ab.esp"));
+        assert!(!glob_matches("Data?a.esp", "Data/a.esp"));
+    }
+
+    #[test]
+    fn double_stars_cross_directories() {
+        assert!(glob_matches("Data/**/*.dds", "Data/textures/a.dds"));
+        assert!(glob_matches("Data/**/*.dds", "Data/textures/armor/a.dds"));
+        assert!(glob_matches("Data/**/*.dds", "Data/a.dds"));
+        assert!(glob_matches("**", "Data/textures/a.dds"));
+        assert!(!glob_matches("Data/**/*.dds", "Data/textures/a.nif"));
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::deploy;
+use super::digest;
+use super::file_ownership;
+use super::file_ownership::Provider;
+use super::plugins;
+use super::profile::ProfileFile;
+use super::tes4;
+use super::tes4::{PluginHeader, Record};
+use super::transaction::Transaction;
+
+/// The pseudo-mod the merged patch is installed as
+pub(crate) const PATCH_MOD: &str = "pipboy/merged-patch";
+/// File name of the merged patch plugin
+pub(crate) const PATCH_PLUGIN: &str = "pipboy Merged Patch.esp";
+
+/// Record types of leveled items, NPCs and creatures
+const LEVELED_LISTS: [&[u8; 4]; 3] = [b"LVLI", b"LVLN", b"LVLC"];
+/// Subrecords that come before the entries of a leveled list
+const HEAD_SUBRECORDS: [&[u8; 4]; 4] = [b"EDID", b"OBND", b"LVLD", b"LVLF"];
+/// Alternate texture subrecords hold FormIDs nobody remaps, and only matter to the editor
+const DROPPED_SUBRECORDS: [&[u8; 4]; 2] = [b"MODS", b"MODD"];
+
+/// A FormID as the plugin that defined it and the ID within that plugin, which means the
+/// same thing whichever plugin it is read from. The null FormID has no plugin.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct GlobalId {
+    plugin: String,
+    object_id: u32,
+}
+
+/// What the owner of an entry requires, the second field of a COED subrecord
+#[derive(Clone, PartialEq)]
+enum Requirement {
+    /// Global variable, for NPC owners
+    Global(GlobalId),
+    /// Rank, for faction owners, kept as it was read
+    Rank([u8; 4]),
+}
+
+/// The COED subrecord of an entry
+#[derive(Clone, PartialEq)]
+struct Ownership {
+    owner: GlobalId,
+    requirement: Requirement,
+    /// Item condition
+    rest: Vec<u8>,
+}
+
+/// One entry of a leveled list
+#[derive(Clone, PartialEq)]
+struct Entry {
+    level: i16,
+    reference: GlobalId,
+    count: i16,
+    ownership: Option<Ownership>,
+}
+
+/// One plugin's version of a leveled list
+struct LeveledList {
+    plugin: String,
+    flags: u32,
+    /// Every subrecord that isn't an entry or the global, in order
+    fields: Vec<([u8; 4], Vec<u8>)>,
+    /// Global variable that overrides the chance none, leveled items only
+    global: Option<GlobalId>,
+    entries: Vec<Entry>,
+}
+
+/// Every active plugin's version of each leveled list, keyed by record
+type Versions = BTreeMap<(GlobalId, [u8; 4]), Vec<LeveledList>>;
+
+/// Record type of every record in the active plugins
+type Kinds = BTreeMap<GlobalId, [u8; 4]>;
+
+fn global_id(header: &PluginHeader, plugin: &str, active: &[&String], form_id: u32) -> GlobalId {
+    if form_id == 0 {
+        return GlobalId { plugin: String::new(), object_id: 0 };
+    }
+    let (origin, object_id) = header.origin(plugin, form_id);
+    // Masters are named however the plugin's author typed them
+    let plugin = match active.iter().find(|item| item.eq_ignore_ascii_case(origin)) {
+        Some(name) => name.to_string(),
+        None => origin.to_string()
+    };
+    GlobalId { plugin, object_id }
+}
+
+fn parse_list(header: &PluginHeader, plugin: &str, active: &[&String], kinds: &Kinds, record: &Record) -> Result<LeveledList, String> {
+    let mut list = LeveledList { plugin: plugin.to_string(), flags: record.flags, fields: Vec::new(), global: None, entries: Vec::new() };
+    for (kind, data) in tes4::subrecords(&record.data)? {
+        match &kind {
+            b"LVLO" if data.len() >= 12 => list.entries.push(Entry {
+                level: i16::from_le_bytes([data[0], data[1]]),
+                reference: global_id(header, plugin, active, tes4::read_u32(data, 4)),
+                count: i16::from_le_bytes([data[8], data[9]]),
+                ownership: None,
+            }),
+            b"COED" if data.len() >= 8 => if let Some(entry) = list.entries.last_mut() {
+                let owner = global_id(header, plugin, active, tes4::read_u32(data, 0));
+                // NPC owners require a global variable and faction owners a rank
+                let requirement = match kinds.get(&owner) {
+                    Some(b"NPC_") => Requirement::Global(global_id(header, plugin, active, tes4::read_u32(data, 4))),
+                    _ => Requirement::Rank([data[4], data[5], data[6], data[7]])
+                };
+                entry.ownership = Some(Ownership { owner, requirement, rest: data[8..].to_vec() });
+            },
+            b"LVLG" if data.len() >= 4 => list.global = Some(global_id(header, plugin, active, tes4::read_u32(data, 0))),
+            kind if DROPPED_SUBRECORDS.contains(&kind) => {}
+            _ => list.fields.push((kind, data.to_vec()))
+        }
+    }
+    Ok(list)
+}
+
+/// Read every version of every leveled list in the active plugins, in load order
+fn read_leveled_lists(profile: &ProfileFile) -> Result<(Vec<String>, Versions), String> {
+    let on_disk = plugins::plugins_on_disk(&profile.install_path);
+    // The patch must not merge its own previous build
+    let active: Vec<&String> = profile.load_order.iter()
+        .filter(|plugin| on_disk.contains(plugin) && profile.is_plugin_active(plugin) && *plugin != PATCH_PLUGIN)
+        .collect();
+    let mut read = Vec::new();
+    let mut kinds: Kinds = BTreeMap::new();
+    for plugin in &active {
+        let path = format!("{}/Data/{}", profile.install_path, plugin);
+        let (header, records) = match tes4::read_records(&path, |kind| LEVELED_LISTS.contains(&kind)) {
+            Ok(read) => read,
+            Err(issue) => return Err(format!("Failed to read records of {} <- {}", plugin, issue))
+        };
+        for record in &records {
+            kinds.insert(global_id(&header, plugin, &active, record.form_id), record.kind);
+        }
+        read.push((plugin, header, records));
+    }
+    // Owners can be defined after the lists that use them, so lists are only parsed once every kind is known
+    let mut versions: Versions = BTreeMap::new();
+    for (plugin, header, records) in &read {
+        for record in records.iter().filter(|record| LEVELED_LISTS.contains(&&record.kind)) {
+            let list = match parse_list(header, plugin, &active, &kinds, record) {
+                Ok(list) => list,
+                Err(issue) => return Err(format!("Failed to read {} {:08X} of {} <- {}", String::from_utf8_lossy(&record.kind), record.form_id, plugin, issue))
+            };
+            versions.entry((global_id(header, plugin, &active, record.form_id), record.kind)).or_default().push(list);
+        }
+    }
+    Ok((active.into_iter().cloned().collect(), versions))
+}
+
+fn count(entries: &[Entry], entry: &Entry) -> usize {
+    entries.iter().filter(|item| *item == entry).count()
+}
+
+/// Merge the entries of every version of a list
+///
+/// Each entry appears as many times as the plugin that added the most copies has it, or
+/// if no plugin added any, as few times as the plugin that removed the most left it.
+/// Additions win over removals.
+fn merge_entries(base: &LeveledList, overrides: &[&LeveledList]) -> Vec<Entry> {
+    let mut distinct: Vec<&Entry> = Vec::new();
+    for entry in base.entries.iter().chain(overrides.iter().flat_map(|list| list.entries.iter())) {
+        if !distinct.contains(&entry) {
+            distinct.push(entry);
+        }
+    }
+    let mut merged = Vec::new();
+    for entry in distinct {
+        let in_base = count(&base.entries, entry);
+        let counts: Vec<usize> = overrides.iter().map(|list| count(&list.entries, entry)).collect();
+        let most = counts.iter().cloned().max().unwrap_or(in_base);
+        let least = counts.iter().cloned().min().unwrap_or(in_base);
+        let wanted = if most > in_base { most } else { least };
+        merged.extend(std::iter::repeat_n(entry.clone(), wanted));
+    }
+    // The game expects entries sorted by level
+    merged.sort_by_key(|entry| entry.level);
+    merged
+}
+
+/// FormID of a global ID in the patch, whose masters are `masters`
+fn local_form_id(masters: &[String], id: &GlobalId) -> Result<u32, String> {
+    if id.plugin.is_empty() {
+        return Ok(0);
+    }
+    match masters.iter().position(|master| *master == id.plugin) {
+        Some(index) => Ok(((index as u32) << 24) | id.object_id),
+        None => Err(format!("{:06X} is from {}, which is not a master of the patch", id.object_id, id.plugin))
+    }
+}
+
+fn list_data(masters: &[String], winner: &LeveledList, entries: &[Entry]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    for (kind, field) in winner.fields.iter().filter(|(kind, _)| HEAD_SUBRECORDS.contains(&kind)) {
+        tes4::write_subrecord(&mut data, kind, field);
+    }
+    if let Some(global) = &winner.global {
+        tes4::write_subrecord(&mut data, b"LVLG", &local_form_id(masters, global)?.to_le_bytes());
+    }
+    for entry in entries {
+        let mut lvlo = Vec::new();
+        lvlo.extend_from_slice(&entry.level.to_le_bytes());
+        lvlo.extend_from_slice(&[0u8; 2]);
+        lvlo.extend_from_slice(&local_form_id(masters, &entry.reference)?.to_le_bytes());
+        lvlo.extend_from_slice(&entry.count.to_le_bytes());
+        lvlo.extend_from_slice(&[0u8; 2]);
+        tes4::write_subrecord(&mut data, b"LVLO", &lvlo);
+        if let Some(ownership) = &entry.ownership {
+            let mut coed = local_form_id(masters, &ownership.owner)?.to_le_bytes().to_vec();
+            match &ownership.requirement {
+                Requirement::Global(global) => coed.extend_from_slice(&local_form_id(masters, global)?.to_le_bytes()),
+                Requirement::Rank(rank) => coed.extend_from_slice(rank)
+            }
+            coed.extend_from_slice(&ownership.rest);
+            tes4::write_subrecord(&mut data, b"COED", &coed);
+        }
+    }
+    for (kind, field) in winner.fields.iter().filter(|(kind, _)| !HEAD_SUBRECORDS.contains(&kind)) {
+        tes4::write_subrecord(&mut data, kind, field);
+    }
+    Ok(data)
+}
+
+/// Every plugin a merged list refers to
+fn referenced_plugins<'a>(id: &'a GlobalId, winner: &'a LeveledList, entries: &'a [Entry]) -> Vec<&'a str> {
+    let mut ids = vec![id];
+    ids.extend(winner.global.iter());
+    for entry in entries {
+        ids.push(&entry.reference);
+        if let Some(ownership) = &entry.ownership {
+            ids.push(&ownership.owner);
+            if let Requirement::Global(global) = &ownership.requirement {
+                ids.push(global);
+            }
+        }
+    }
+    ids.iter().map(|id| id.plugin.as_str()).filter(|plugin| !plugin.is_empty()).collect()
+}
+
+/// Build the merged patch from the active plugins
+///
+/// Only lists that two or more plugins override, and whose merged entries differ from the
+/// winning version's, go in the patch. Returns the plugin and the number of merged lists.
+fn build_plugin(profile: &ProfileFile) -> Result<(Vec<u8>, usize), String> {
+    let (active, versions) = read_leveled_lists(profile)?;
+    let mut merged_lists = Vec::new();
+    for ((id, kind), lists) in &versions {
+        let (base, overrides): (Vec<&LeveledList>, Vec<&LeveledList>) = lists.iter().partition(|list| list.plugin == id.plugin);
+        // Without the plugin that defined the list, the first override stands in for it
+        let (base, overrides) = match base.first() {
+            Some(base) => (*base, overrides),
+            None => (overrides[0], overrides[1..].to_vec())
+        };
+        if overrides.len() < 2 {
+            continue;
+        }
+        let winner = *overrides.last().unwrap();
+        let entries = merge_entries(base, &overrides);
+        if entries == winner.entries {
+            continue;
+        }
+        let referenced = referenced_plugins(id, winner, &entries);
+        if let Some(missing) = referenced.iter().find(|plugin| !active.iter().any(|item| item == *plugin)) {
+            println!("Not merging {} {:06X}, it refers to {} which is not active", String::from_utf8_lossy(kind), id.object_id, missing);
+            continue;
+        }
+        merged_lists.push((id, kind, winner, entries));
+    }
+    // Masters keep their load order, and only the ones the patch refers to are listed
+    let masters: Vec<String> = active.iter()
+        .filter(|plugin| merged_lists.iter().any(|(id, _, winner, entries)| referenced_plugins(id, winner, entries).contains(&plugin.as_str())))
+        .cloned()
+        .collect();
+    let mut groups = Vec::new();
+    let mut record_count = 0;
+    for group_kind in LEVELED_LISTS {
+        let mut records = Vec::new();
+        for (id, _, winner, entries) in merged_lists.iter().filter(|(_, kind, _, _)| *kind == group_kind) {
+            let written = local_form_id(&masters, id).and_then(|form_id| Ok((form_id, list_data(&masters, winner, entries)?)));
+            let (form_id, data) = match written {
+                Ok(written) => written,
+                Err(issue) => return Err(format!("Failed to write {} {:06X} <- {}", String::from_utf8_lossy(group_kind), id.object_id, issue))
+            };
+            tes4::write_record(&mut records, group_kind, winner.flags, form_id, &data);
+            record_count += 1;
+        }
+        if !records.is_empty() {
+            tes4::write_group(&mut groups, group_kind, &records);
+            record_count += 1;
+        }
+    }
+    let mut plugin = tes4::header_bytes("pipboy", "Merged leveled lists generated by pipboy", &masters, record_count);
+    plugin.extend(groups);
+    Ok((plugin, merged_lists.len()))
+}
+
+/// Whether the merged patch is installed and enabled
+pub(crate) fn patch_installed(profile: &ProfileFile) -> bool {
+    profile.enabled_mods.iter().any(|item| item == PATCH_MOD) && profile.is_enabled(PATCH_MOD)
+}
+
+/// Build the merged patch and install it as a pseudo-mod with the highest priority,
+/// deploying it and loading it last
+///
+/// Returns the number of merged lists.
+pub(crate) fn build_patch(config_path: &str, profile: &mut ProfileFile, transaction: &mut Transaction) -> Result<usize, String> {
+    // Installing the patch deploys every mod, which would undo the purge
+    if deploy::is_purged(config_path, profile)? {
+        return Err("The game directory was purged. Deploy the mods before building the patch".to_string());
+    }
+    let (plugin, merged) = build_plugin(profile)?;
+    let file = format!("Data/{}", PATCH_PLUGIN);
+    // The staged copy is replaced rather than written over, which would change a hardlinked copy in Data too
+    let staged = format!("{}/{}", deploy::staging_path(config_path, PATCH_MOD), file);
+    let index_path = format!("{}/mods/indices/{}/index", config_path, PATCH_MOD);
+    for path in [&staged, &index_path] {
+        transaction.stage_path(path)?;
+        if let Some(parent) = Path::new(path).parent() {
+            if fs::create_dir_all(parent).is_err() {
+                return Err(format!("Failed to create path {}", parent.display()));
+            }
+        }
+        if Path::new(path).exists() && fs::remove_file(path).is_err() {
+            return Err(format!("Failed to remove {}", path));
+        }
+    }
+    if fs::write(&staged, &plugin).is_err() {
+        return Err(format!("Failed to write {}", staged));
+    }
+    // The index lets the patch be uninstalled like any other mod
+    if fs::write(&index_path, format!("{}\n", file)).is_err() {
+        return Err(format!("Failed to write {}", index_path));
+    }
+    let mut table = file_ownership::load_ownership_table(config_path)?;
+    let installed_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0
+    };
+    let providers = table.files.entry(file).or_default();
+    providers.retain(|provider| provider.owner != PATCH_MOD);
+    providers.push(Provider {
+        owner: PATCH_MOD.to_string(),
+        version: String::new(),
+        sha256: digest::sha256_bytes(&plugin),
+        size: plugin.len() as u64,
+        installed_at,
+    });
+    file_ownership::save_ownership_table(config_path, &table)?;
+    if !profile.enabled_mods.iter().any(|item| item == PATCH_MOD) {
+        profile.enabled_mods.push(PATCH_MOD.to_string());
+    }
+    profile.mod_order.retain(|item| item != PATCH_MOD);
+    profile.mod_order.push(PATCH_MOD.to_string());
+    deploy::deploy(config_path, profile, transaction)?;
+    plugins::sync_load_order(config_path, profile)?;
+    profile.load_order.retain(|item| item != PATCH_PLUGIN);
+    profile.load_order.push(PATCH_PLUGIN.to_string());
+    plugins::write_load_order(config_path, profile, transaction)?;
+    Ok(merged)
+}
+
+/// Rebuild the merged patch if it is installed, after the mods or load order changed
+///
+/// A purged patch is left alone, the next deployment brings it back and rebuilds it.
+pub(crate) fn refresh_patch(config_path: &str, profile: &mut ProfileFile, transaction: &mut Transaction) -> Result<(), String> {
+    let deployed = format!("{}/Data/{}", profile.install_path, PATCH_PLUGIN);
+    if !patch_installed(profile) || fs::symlink_metadata(&deployed).is_err() {
+        return Ok(());
+    }
+    let merged = build_patch(config_path, profile, transaction)?;
+    println!("Rebuilt {} with {} merged leveled list{}", PATCH_PLUGIN, merged, if merged == 1 { "" } else { "s" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(level: i16, object_id: u32, count: i16) -> Entry {
+        Entry { level, reference: GlobalId { plugin: "FalloutNV.esm".to_string(), object_id }, count, ownership: None }
+    }
+
+    fn list(plugin: &str, entries: Vec<Entry>) -> LeveledList {
+        LeveledList { plugin: plugin.to_string(), flags: 0, fields: Vec::new(), global: None, entries }
+    }
+
+    fn ids(entries: &[Entry]) -> Vec<u32> {
+        entries.iter().map(|entry| entry.reference.object_id).collect()
+    }
+
+    #[test]
+    fn additions_from_every_plugin_are_kept() {
+        let base = list("FalloutNV.esm", vec![entry(1, 1, 1)]);
+        let first = list("A.esp", vec![entry(1, 1, 1), entry(5, 2, 1)]);
+        let second = list("B.esp", vec![entry(1, 1, 1), entry(3, 3, 1)]);
+        assert_eq!(ids(&merge_entries(&base, &[&first, &second])), vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn removals_are_kept_unless_another_plugin_adds_the_entry() {
+        let base = list("FalloutNV.esm", vec![entry(1, 1, 1), entry(2, 2, 1)]);
+        let removes = list("A.esp", vec![entry(2, 2, 1)]);
+        let untouched = list("B.esp", vec![entry(1, 1, 1), entry(2, 2, 1)]);
+        assert_eq!(ids(&merge_entries(&base, &[&removes, &untouched])), vec![2]);
+        let adds = list("C.esp", vec![entry(1, 1, 1), entry(1, 1, 1), entry(2, 2, 1)]);
+        assert_eq!(ids(&merge_entries(&base, &[&removes, &adds])), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn duplicates_follow_the_plugin_with_the_most_or_fewest_copies() {
+        let base = list("FalloutNV.esm", vec![entry(1, 1, 1), entry(1, 1, 1), entry(1, 1, 1)]);
+        let fewer = list("A.esp", vec![entry(1, 1, 1), entry(1, 1, 1)]);
+        let fewest = list("B.esp", vec![entry(1, 1, 1)]);
+        assert_eq!(merge_entries(&base, &[&fewer, &fewest]).len(), 1);
+        let more = list("C.esp", vec![entry(1, 1, 1); 4]);
+        assert_eq!(merge_entries(&base, &[&fewest, &more]).len(), 4);
+    }
+
+    #[test]
+    fn entries_differing_in_count_are_different_entries() {
+        let base = list("FalloutNV.esm", vec![entry(1, 1, 1)]);
+        let changed = list("A.esp", vec![entry(1, 1, 5)]);
+        let untouched = list("B.esp", vec![entry(1, 1, 1)]);
+        let merged = merge_entries(&base, &[&changed, &untouched]);
+        assert_eq!(merged.iter().map(|entry| entry.count).collect::<Vec<i16>>(), vec![5]);
+    }
+
+    #[test]
+    fn form_ids_from_unlisted_plugins_are_refused() {
+        let masters = vec!["FalloutNV.esm".to_string(), "A.esp".to_string()];
+        let id = |plugin: &str| GlobalId { plugin: plugin.to_string(), object_id: 0xABC };
+        assert_eq!(local_form_id(&masters, &id("A.esp")), Ok(0x0100_0ABC));
+        assert_eq!(local_form_id(&masters, &id("")), Ok(0));
+        assert!(local_form_id(&masters, &id("B.esp")).is_err());
+    }
+
+    fn coed_of(data: &[u8]) -> Vec<u8> {
+        tes4::subrecords(data).unwrap().into_iter().find(|(kind, _)| kind == b"COED").unwrap().1.to_vec()
+    }
+
+    #[test]
+    fn ownership_form_ids_are_remapped() {
+        // Mod.esp is not a master of itself, so its own NPC has the index after its masters
+        let header = PluginHeader {
+            flags: 0,
+            record_count: 0,
+            author: String::new(),
+            description: String::new(),
+            masters: vec!["FalloutNV.esm".to_string()],
+        };
+        let (fnv, other, own) = ("FalloutNV.esm".to_string(), "Other.esp".to_string(), "Mod.esp".to_string());
+        let active = vec![&fnv, &other, &own];
+        let mut kinds = Kinds::new();
+        kinds.insert(GlobalId { plugin: own.clone(), object_id: 0x123 }, *b"NPC_");
+        kinds.insert(GlobalId { plugin: fnv.clone(), object_id: 0x777 }, *b"FACT");
+        let mut data = Vec::new();
+        let mut lvlo = Vec::new();
+        lvlo.extend_from_slice(&1i16.to_le_bytes());
+        lvlo.extend_from_slice(&[0u8; 2]);
+        lvlo.extend_from_slice(&0x0000_0010u32.to_le_bytes());
+        lvlo.extend_from_slice(&1i16.to_le_bytes());
+        lvlo.extend_from_slice(&[0u8; 2]);
+        let condition = 1.0f32.to_le_bytes();
+        for (owner, requirement) in [(0x0100_0123u32, 0x0000_0456u32), (0x0000_0777, 3)] {
+            tes4::write_subrecord(&mut data, b"LVLO", &lvlo);
+            let mut coed = owner.to_le_bytes().to_vec();
+            coed.extend_from_slice(&requirement.to_le_bytes());
+            coed.extend_from_slice(&condition);
+            tes4::write_subrecord(&mut data, b"COED", &coed);
+        }
+        let record = Record { kind: *b"LVLI", flags: 0, form_id: 0x0000_0900, data };
+        let list = parse_list(&header, &own, &active, &kinds, &record).unwrap();
+        assert!(list.entries[0].ownership == Some(Ownership {
+            owner: GlobalId { plugin: own.clone(), object_id: 0x123 },
+            requirement: Requirement::Global(GlobalId { plugin: fnv.clone(), object_id: 0x456 }),
+            rest: condition.to_vec(),
+        }));
+
+        let masters = vec![fnv.clone(), other.clone(), own.clone()];
+        let written = list_data(&masters, &list, &list.entries[..1]).unwrap();
+        let mut expected = 0x0200_0123u32.to_le_bytes().to_vec();
+        expected.extend_from_slice(&0x0000_0456u32.to_le_bytes());
+        expected.extend_from_slice(&condition);
+        assert_eq!(coed_of(&written), expected);
+        // Faction ranks are not FormIDs and are left alone
+        let written = list_data(&masters, &list, &list.entries[1..]).unwrap();
+        let mut expected = 0x0000_0777u32.to_le_bytes().to_vec();
+        expected.extend_from_slice(&3u32.to_le_bytes());
+        expected.extend_from_slice(&condition);
+        assert_eq!(coed_of(&written), expected);
+        // The patch can't refer to an owner whose plugin it doesn't list as a master
+        assert!(list_data(&masters[..2], &list, &list.entries[..1]).is_err());
+    }
+}
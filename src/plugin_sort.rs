@@ -0,0 +1,246 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::masterlist;
+use super::plugins;
+use super::profile::ProfileFile;
+use super::tes4;
+
+/// Where a requirement to load one plugin after another came from, strongest first
+#[derive(Clone, Copy)]
+enum Source {
+    Master,
+    Rule,
+    Masterlist,
+}
+
+/// One plugin having to load after another, by position in the current load order
+struct Edge {
+    plugin: usize,
+    after: usize,
+    source: Source,
+}
+
+impl Edge {
+    fn describe(&self, order: &[String]) -> String {
+        let (plugin, after) = (&order[self.plugin], &order[self.after]);
+        match self.source {
+            Source::Master => format!("{} is a master of {}", after, plugin),
+            Source::Rule => format!("a profile rule loads {} after {}", plugin, after),
+            Source::Masterlist => format!("the masterlist loads {} after {}", plugin, after)
+        }
+    }
+}
+
+/// Whether `to` already has to load after `from`, following accepted edges
+fn loads_after(edges: &[Edge], from: usize, to: usize) -> bool {
+    let mut stack = vec![from];
+    let mut seen = vec![from];
+    while let Some(current) = stack.pop() {
+        if current == to {
+            return true;
+        }
+        for edge in edges.iter().filter(|edge| edge.plugin == current) {
+            if !seen.contains(&edge.after) {
+                seen.push(edge.after);
+                stack.push(edge.after);
+            }
+        }
+    }
+    false
+}
+
+/// Every requirement on the order of the plugins, from plugin masters, then profile rules,
+/// then the masterlist
+fn collect_requirements(profile: &ProfileFile, masterlist_path: Option<&str>) -> Result<Vec<(String, String, Source)>, String> {
+    let mut requirements = Vec::new();
+    for plugin in &profile.load_order {
+        // Plugins of disabled mods aren't in Data to be read
+        if let Ok(header) = tes4::read_header(&format!("{}/Data/{}", profile.install_path, plugin)) {
+            for master in header.masters {
+                requirements.push((plugin.clone(), master, Source::Master));
+            }
+        }
+    }
+    let mut rules: Vec<(&String, &Vec<String>)> = profile.load_after.iter().collect();
+    rules.sort();
+    for (plugin, afters) in rules {
+        for after in afters {
+            requirements.push((plugin.clone(), after.clone(), Source::Rule));
+        }
+    }
+    if let Some(path) = masterlist_path {
+        for (plugin, after) in masterlist::load_rules(path)? {
+            requirements.push((plugin, after, Source::Masterlist));
+        }
+    }
+    Ok(requirements)
+}
+
+/// Change the masterlist `plugins sort` reads rules from, or without a path stop using one
+pub(crate) fn set_masterlist(profile: &mut ProfileFile, path: Option<&str>) -> Result<(), String> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            profile.masterlist.clear();
+            return Ok(());
+        }
+    };
+    // Refuse files that sorting would fail on later
+    let rules = masterlist::load_rules(path)?;
+    let absolute = match std::env::current_dir() {
+        Ok(current) => current.join(path),
+        Err(issue) => return Err(format!("Failed to get current directory <- {}", issue))
+    };
+    profile.masterlist = absolute.to_string_lossy().to_string();
+    println!("Using {} load-after rule{} from {}", rules.len(), if rules.len() == 1 { "" } else { "s" }, profile.masterlist);
+    Ok(())
+}
+
+/// Sort the load order so every plugin loads after its masters and after whatever the
+/// profile rules and masterlist say it should, moving as little as possible
+///
+/// Returns the new order and an explanation of every change. Requirements that would
+/// contradict stronger ones are skipped and explained too.
+pub(crate) fn sort_plugins(profile: &ProfileFile, masterlist_path: Option<&str>) -> Result<(Vec<String>, Vec<String>), String> {
+    let order = &profile.load_order;
+    let index = |name: &str| order.iter().position(|plugin| plugin.eq_ignore_ascii_case(name));
+    let groups: Vec<(usize, usize)> = order.iter().map(|plugin| plugins::load_group(&profile.install_path, plugin)).collect();
+    let mut notes = Vec::new();
+    let mut edges: Vec<Edge> = Vec::new();
+    for (plugin, after, source) in collect_requirements(profile, masterlist_path)? {
+        // Requirements on plugins that aren't installed don't affect anything
+        let (plugin, after) = match (index(&plugin), index(&after)) {
+            (Some(plugin), Some(after)) if plugin != after => (plugin, after),
+            _ => continue
+        };
+        if edges.iter().any(|edge| edge.plugin == plugin && edge.after == after) {
+            continue;
+        }
+        let edge = Edge { plugin, after, source };
+        if groups[plugin] < groups[after] {
+            notes.push(format!("Ignored that {}, because {} has to load before {}", edge.describe(order), order[plugin], order[after]));
+        } else if loads_after(&edges, after, plugin) {
+            notes.push(format!("Ignored that {}, because it contradicts the requirements before it", edge.describe(order)));
+        } else {
+            edges.push(edge);
+        }
+    }
+    // Place plugins one at a time, always taking the earliest one whose requirements are met
+    let mut placed: Vec<usize> = Vec::new();
+    while placed.len() < order.len() {
+        let next = (0..order.len())
+            .filter(|plugin| !placed.contains(plugin))
+            .filter(|plugin| edges.iter().all(|edge| edge.plugin != *plugin || placed.contains(&edge.after)))
+            .min_by_key(|plugin| (groups[*plugin], *plugin));
+        match next {
+            Some(next) => placed.push(next),
+            None => return Err("Plugin requirements form a cycle".to_string())
+        }
+    }
+    for (new_position, plugin) in placed.iter().enumerate() {
+        if new_position == *plugin {
+            continue;
+        }
+        // A requirement moved this plugin if it wasn't met before
+        let reasons: Vec<String> = edges.iter()
+            .filter(|edge| edge.plugin == *plugin && edge.after > *plugin)
+            .map(|edge| edge.describe(order))
+            .collect();
+        let reason = if reasons.is_empty() {
+            "shifted by the other moves".to_string()
+        } else {
+            reasons.join(", ")
+        };
+        notes.push(format!("Moved {} from {} to {}: {}", order[*plugin], plugin + 1, new_position + 1, reason));
+    }
+    Ok((placed.iter().map(|plugin| order[*plugin].clone()).collect(), notes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A profile whose Data holds the given plugins, each with its masters
+    fn profile_with(name: &str, plugins: &[(&str, &[&str])]) -> ProfileFile {
+        let install_path = std::env::temp_dir().join(format!("pipboy-sort-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&install_path);
+        fs::create_dir_all(install_path.join("Data")).unwrap();
+        for (plugin, masters) in plugins {
+            let masters: Vec<String> = masters.iter().map(|master| master.to_string()).collect();
+            fs::write(install_path.join("Data").join(plugin), tes4::header_bytes("", "", &masters, 0)).unwrap();
+        }
+        ProfileFile {
+            install_path: install_path.to_string_lossy().to_string(),
+            load_order: plugins.iter().map(|(plugin, _)| plugin.to_string()).collect(),
+            ..ProfileFile::default()
+        }
+    }
+
+    #[test]
+    fn plugins_load_after_their_masters() {
+        let profile = profile_with("masters", &[
+            ("FalloutNV.esm", &[]),
+            ("A.esp", &["FalloutNV.esm", "B.esp"]),
+            ("B.esp", &["FalloutNV.esm"]),
+            ("C.esp", &[]),
+        ]);
+        let (order, notes) = sort_plugins(&profile, None).unwrap();
+        fs::remove_dir_all(&profile.install_path).unwrap();
+        assert_eq!(order, vec!["FalloutNV.esm", "B.esp", "A.esp", "C.esp"]);
+        assert_eq!(notes, vec![
+            "Moved B.esp from 3 to 2: shifted by the other moves",
+            "Moved A.esp from 2 to 3: B.esp is a master of A.esp",
+        ]);
+    }
+
+    #[test]
+    fn sorted_orders_are_left_alone() {
+        let profile = profile_with("sorted", &[("FalloutNV.esm", &[]), ("A.esp", &["FalloutNV.esm"])]);
+        let (order, notes) = sort_plugins(&profile, None).unwrap();
+        fs::remove_dir_all(&profile.install_path).unwrap();
+        assert_eq!(order, profile.load_order);
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn load_after_cycles_are_broken_at_the_later_rule() {
+        let mut profile = profile_with("cycle", &[("A.esp", &[]), ("B.esp", &[]), ("C.esp", &[])]);
+        profile.load_after.insert("A.esp".to_string(), vec!["B.esp".to_string()]);
+        profile.load_after.insert("B.esp".to_string(), vec!["C.esp".to_string()]);
+        profile.load_after.insert("C.esp".to_string(), vec!["A.esp".to_string()]);
+        let (order, notes) = sort_plugins(&profile, None).unwrap();
+        fs::remove_dir_all(&profile.install_path).unwrap();
+        assert_eq!(order, vec!["C.esp", "B.esp", "A.esp"]);
+        assert_eq!(notes[0], "Ignored that a profile rule loads C.esp after A.esp, because it contradicts the requirements before it");
+    }
+
+    #[test]
+    fn rules_never_outrank_masters() {
+        let mut profile = profile_with("outranked", &[("FalloutNV.esm", &[]), ("B.esp", &[]), ("A.esp", &["B.esp"])]);
+        profile.load_after.insert("B.esp".to_string(), vec!["A.esp".to_string()]);
+        profile.load_after.insert("FalloutNV.esm".to_string(), vec!["A.esp".to_string()]);
+        let (order, notes) = sort_plugins(&profile, None).unwrap();
+        fs::remove_dir_all(&profile.install_path).unwrap();
+        assert_eq!(order, profile.load_order);
+        assert_eq!(notes, vec![
+            "Ignored that a profile rule loads B.esp after A.esp, because it contradicts the requirements before it",
+            "Ignored that a profile rule loads FalloutNV.esm after A.esp, because FalloutNV.esm has to load before A.esp",
+        ]);
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::file_ownership;
+use super::file_ownership::OwnershipTable;
+use super::profile::ProfileFile;
+use super::tes4;
+use super::transaction::Transaction;
+
+/// Masters of the base game and its DLC, which always load first and in this order
+const GAME_MASTERS: [&str; 10] = [
+    "FalloutNV.esm",
+    "DeadMoney.esm",
+    "HonestHearts.esm",
+    "OldWorldBlues.esm",
+    "LonesomeRoad.esm",
+    "GunRunnersArsenal.esm",
+    "ClassicPack.esm",
+    "MercenaryPack.esm",
+    "TribalPack.esm",
+    "CaravanPack.esm",
+];
+
+/// Modification time given to the first plugin, the release date of Fallout: New Vegas.
+/// The game loads plugins in order of modification time.
+const BASE_TIMESTAMP: u64 = 1287446400;
+
+/// Whether a plugin loads along with the masters, either by extension or by its ESM flag
+fn is_master(install_path: &str, plugin: &str) -> bool {
+    plugin.to_lowercase().ends_with(".esm") || match tes4::read_header(&format!("{}/Data/{}", install_path, plugin)) {
+        Ok(header) => header.is_master(),
+        Err(_) => false
+    }
+}
+
+fn is_plugin(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".esm") || name.ends_with(".esp")
+}
+
+/// The plugin name of a path relative to the game directory, if it is a plugin the game loads
+pub(crate) fn plugin_name(file: &str) -> Option<&str> {
+    match file.split_once('/') {
+        Some((data, name)) if data.eq_ignore_ascii_case("Data") && !name.contains('/') && is_plugin(name) => Some(name),
+        _ => None
+    }
+}
+
+fn game_master_position(plugin: &str) -> Option<usize> {
+    GAME_MASTERS.iter().position(|master| master.eq_ignore_ascii_case(plugin))
+}
+
+/// Plugins that installed mods ship, whether or not they are enabled
+fn provided_plugins(table: &OwnershipTable) -> Vec<String> {
+    table.files.keys().filter_map(|file| plugin_name(file)).map(|name| name.to_string()).collect()
+}
+
+/// Plugins currently in the Data directory
+pub(crate) fn plugins_on_disk(install_path: &str) -> Vec<String> {
+    let mut plugins: Vec<String> = match fs::read_dir(format!("{}/Data", install_path)) {
+        Ok(entries) => entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| is_plugin(name) && fs::metadata(format!("{}/Data/{}", install_path, name)).map(|metadata| metadata.is_file()).unwrap_or(false))
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    plugins.sort();
+    plugins
+}
+
+/// Where a plugin has to load relative to others: game masters first in a fixed order,
+/// then other masters, then everything else
+pub(crate) fn load_group(install_path: &str, plugin: &str) -> (usize, usize) {
+    match game_master_position(plugin) {
+        Some(position) => (0, position),
+        None if is_master(install_path, plugin) => (1, 0),
+        None => (2, 0)
+    }
+}
+
+/// Keep game masters at the front and every master ahead of every plugin, otherwise
+/// leaving the order alone
+fn enforce_master_order(install_path: &str, load_order: &mut [String]) {
+    load_order.sort_by_cached_key(|plugin| load_group(install_path, plugin));
+}
+
+/// Bring the load order up to date with the plugins that are installed or in Data
+///
+/// New masters go after the last master and other new plugins go last. Plugins of disabled mods
+/// keep their place so they load in the same spot when the mod is enabled again.
+pub(crate) fn sync_load_order(config_path: &str, profile: &mut ProfileFile) -> Result<(), String> {
+    let table = file_ownership::load_ownership_table(config_path)?;
+    let mut known = provided_plugins(&table);
+    known.extend(plugins_on_disk(&profile.install_path));
+    profile.load_order.retain(|plugin| known.contains(plugin));
+    profile.inactive_plugins.retain(|plugin| known.contains(plugin));
+    known.sort_by_key(|plugin| game_master_position(plugin));
+    for plugin in known {
+        if !profile.load_order.contains(&plugin) {
+            profile.load_order.push(plugin);
+        }
+    }
+    // Sorting is stable, so new masters end up after the last master
+    enforce_master_order(&profile.install_path, &mut profile.load_order);
+    Ok(())
+}
+
+/// Where the game looks for plugins.txt for this profile
+pub(crate) fn plugins_directory(config_path: &str, profile: &ProfileFile) -> Result<String, String> {
+    if profile.plugins_directory.is_empty() {
+        file_ownership::profile_directory(config_path)
+    } else {
+        Ok(profile.plugins_directory.clone())
+    }
+}
+
+/// Write plugins.txt and loadorder.txt and give the plugins in Data ascending
+/// modification times in load order
+///
+/// The game follows symlinks and reads the time of the staged copy, so that is the one set
+/// for symlinked plugins.
+pub(crate) fn write_load_order(config_path: &str, profile: &ProfileFile, transaction: &mut Transaction) -> Result<(), String> {
+    let on_disk = plugins_on_disk(&profile.install_path);
+    let loaded: Vec<&String> = profile.load_order.iter().filter(|plugin| on_disk.contains(plugin)).collect();
+    let directory = plugins_directory(config_path, profile)?;
+    if fs::create_dir_all(&directory).is_err() {
+        return Err(format!("Failed to create path {}", directory));
+    }
+    // The game reads these files on Windows, so use its line endings
+    let mut plugins_txt = String::from("# This file is used to tell Fallout which data files to load.\r\n");
+    let mut loadorder_txt = String::new();
+    for plugin in &loaded {
+        if profile.is_plugin_active(plugin) {
+            plugins_txt.push_str(&format!("{}\r\n", plugin));
+        }
+        loadorder_txt.push_str(&format!("{}\r\n", plugin));
+    }
+    for (name, contents) in [("plugins.txt", plugins_txt), ("loadorder.txt", loadorder_txt)] {
+        let path = format!("{}/{}", directory, name);
+        transaction.stage_path(&path)?;
+        if fs::write(&path, contents).is_err() {
+            return Err(format!("Failed to write {}", path));
+        }
+    }
+    println!("Wrote plugins.txt and loadorder.txt to {}", directory);
+    for (position, plugin) in loaded.iter().enumerate() {
+        let path = format!("{}/Data/{}", profile.install_path, plugin);
+        let path = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => match fs::canonicalize(&path) {
+                Ok(target) => target.to_string_lossy().to_string(),
+                Err(issue) => return Err(format!("Failed to follow link {} <- {}", path, issue))
+            },
+            _ => path
+        };
+        transaction.stage_modified(&path)?;
+        let time = UNIX_EPOCH + Duration::from_secs(BASE_TIMESTAMP + 60 * position as u64);
+        let result = fs::File::open(&path).and_then(|file| file.set_modified(time));
+        if let Err(issue) = result {
+            return Err(format!("Failed to set modification time of {} <- {}", path, issue));
+        }
+    }
+    Ok(())
+}
+
+/// Change where plugins.txt and loadorder.txt are written, or without a directory go back
+/// to the profile directory
+pub(crate) fn set_plugins_directory(profile: &mut ProfileFile, directory: Option<&str>) -> Result<(), String> {
+    let directory = match directory {
+        Some(directory) => directory,
+        None => {
+            profile.plugins_directory.clear();
+            return Ok(());
+        }
+    };
+    let path = Path::new(directory);
+    if path.exists() && !path.is_dir() {
+        return Err(format!("{} is not a directory", directory));
+    }
+    // The load order is written from wherever pipboy runs, so keep the path absolute
+    let absolute = match std::env::current_dir() {
+        Ok(current) => current.join(path),
+        Err(issue) => return Err(format!("Failed to get current directory <- {}", issue))
+    };
+    profile.plugins_directory = absolute.to_string_lossy().to_string();
+    Ok(())
+}
+
+/// Sync the load order with the installed plugins and write it out
+pub(crate) fn update_load_order(config_path: &str, profile: &mut ProfileFile, transaction: &mut Transaction) -> Result<(), String> {
+    sync_load_order(config_path, profile)?;
+    write_load_order(config_path, profile, transaction)
+}
+
+/// The name a plugin goes by in the load order, matched without regard to case
+fn find_plugin(profile: &ProfileFile, name: &str) -> Result<String, String> {
+    match profile.load_order.iter().find(|plugin| plugin.eq_ignore_ascii_case(name)) {
+        Some(plugin) => Ok(plugin.clone()),
+        None => Err(format!("{} is not in the load order", name))
+    }
+}
+
+/// Activate or deactivate plugins
+pub(crate) fn set_active(profile: &mut ProfileFile, names: &[&str], active: bool) -> Result<(), String> {
+    for name in names {
+        let plugin = find_plugin(profile, name)?;
+        profile.inactive_plugins.retain(|item| *item != plugin);
+        if !active {
+            profile.inactive_plugins.push(plugin.clone());
+        }
+        println!("{} {}", if active { "Activated" } else { "Deactivated" }, plugin);
+    }
+    Ok(())
+}
+
+/// Move a plugin to a position in the load order, where 1 loads first
+pub(crate) fn move_plugin(profile: &mut ProfileFile, name: &str, position: usize) -> Result<(), String> {
+    let plugin = find_plugin(profile, name)?;
+    if position == 0 || position > profile.load_order.len() {
+        return Err(format!("Position must be between 1 and {}", profile.load_order.len()));
+    }
+    profile.load_order.retain(|item| *item != plugin);
+    profile.load_order.insert(position - 1, plugin.clone());
+    enforce_master_order(&profile.install_path, &mut profile.load_order);
+    let actual = profile.load_order.iter().position(|item| *item == plugin).unwrap() + 1;
+    if actual != position {
+        println!("Masters load before every other plugin, so {} was moved to position {}", plugin, actual);
+    }
+    Ok(())
+}
+
+/// Make a plugin load after others whenever the load order is sorted
+///
+/// Rules may name plugins that aren't installed yet.
+pub(crate) fn add_rules(profile: &mut ProfileFile, plugin: &str, afters: &[&str]) {
+    let plugin = find_plugin(profile, plugin).unwrap_or_else(|_| plugin.to_string());
+    let afters: Vec<String> = afters.iter().map(|after| find_plugin(profile, after).unwrap_or_else(|_| after.to_string())).collect();
+    let key = match profile.load_after.keys().find(|name| name.eq_ignore_ascii_case(&plugin)) {
+        Some(key) => key.clone(),
+        None => plugin
+    };
+    let rules = profile.load_after.entry(key.clone()).or_default();
+    for after in afters {
+        if !rules.iter().any(|item| item.eq_ignore_ascii_case(&after)) {
+            println!("{} will load after {}", key, after);
+            rules.push(after);
+        }
+    }
+}
+
+/// Drop load-after rules of a plugin
+pub(crate) fn remove_rules(profile: &mut ProfileFile, plugin: &str, afters: &[&str]) -> Result<(), String> {
+    let (plugin, rules) = match profile.load_after.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case(plugin)) {
+        Some(found) => found,
+        None => return Err(format!("{} has no load-after rules", plugin))
+    };
+    for after in afters {
+        match rules.iter().position(|item| item.eq_ignore_ascii_case(after)) {
+            Some(position) => println!("{} no longer has to load after {}", plugin, rules.remove(position)),
+            None => return Err(format!("{} has no rule to load after {}", plugin, after))
+        }
+    }
+    profile.load_after.retain(|_, rules| !rules.is_empty());
+    Ok(())
+}
+
+/// Print every load-after rule of the profile
+pub(crate) fn list_rules(profile: &ProfileFile) {
+    if profile.load_after.is_empty() {
+        println!("No load-after rules.");
+        return;
+    }
+    let mut plugins: Vec<&String> = profile.load_after.keys().collect();
+    plugins.sort();
+    for plugin in plugins {
+        println!("{} loads after {}", plugin, profile.load_after[plugin].join(", "));
+    }
+}
+
+/// Plugins in Data that the given mods provide
+fn plugins_of_mods(table: &OwnershipTable, mod_names: &[String]) -> Vec<String> {
+    table.files.iter()
+        .filter(|(_, providers)| providers.iter().any(|provider| mod_names.contains(&provider.owner)))
+        .filter_map(|(file, _)| plugin_name(file))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Check that the masters of every active plugin the given mods provide are active and
+/// load ahead of it
+///
+/// Returns the problems that keep a plugin from loading at all, followed by warnings
+/// about masters in the wrong order and headers that could not be read.
+pub(crate) fn check_masters(config_path: &str, profile: &ProfileFile, mod_names: &[String]) -> Result<(Vec<String>, Vec<String>), String> {
+    let table = file_ownership::load_ownership_table(config_path)?;
+    let on_disk = plugins_on_disk(&profile.install_path);
+    let position = |name: &str| profile.load_order.iter()
+        .position(|item| item.eq_ignore_ascii_case(name) && on_disk.contains(item) && profile.is_plugin_active(item));
+    let mut missing = Vec::new();
+    let mut warnings = Vec::new();
+    for plugin in plugins_of_mods(&table, mod_names) {
+        let own_position = match position(&plugin) {
+            Some(own_position) => own_position,
+            None => continue
+        };
+        let header = match tes4::read_header(&format!("{}/Data/{}", profile.install_path, plugin)) {
+            Ok(header) => header,
+            Err(issue) => {
+                warnings.push(format!("Could not check the masters of {} <- {}", plugin, issue));
+                continue;
+            }
+        };
+        for master in &header.masters {
+            match position(master) {
+                None => missing.push(format!("{} requires {}, which is not installed or not active", plugin, master)),
+                Some(master_position) if master_position > own_position => warnings.push(format!("{} requires {}, which loads after it", plugin, master)),
+                Some(_) => {}
+            }
+        }
+    }
+    Ok((missing, warnings))
+}
+
+/// Print the load order along with the index each active plugin loads at
+pub(crate) fn list_plugins(config_path: &str, profile: &ProfileFile) -> Result<(), String> {
+    if profile.load_order.is_empty() {
+        println!("No plugins installed.");
+        return Ok(());
+    }
+    let on_disk = plugins_on_disk(&profile.install_path);
+    let mut index = 0;
+    for (position, plugin) in profile.load_order.iter().enumerate() {
+        let slot = if !on_disk.contains(plugin) {
+            "[mod disabled]".to_string()
+        } else if profile.is_plugin_active(plugin) {
+            index += 1;
+            format!("[{:02X}]", index - 1)
+        } else {
+            "[--]".to_string()
+        };
+        println!("{:>4} {} {}", position + 1, slot, plugin);
+    }
+    println!("Load order is written to {}", plugins_directory(config_path, profile)?);
+    Ok(())
+}
@@ -17,17 +17,56 @@
 
 use std::error::Error;
 use confy;
+use semver::Version;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
 use super::config_file;
+use super::deploy::DeployMethod;
+use super::versions;
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct ProfileFile {
     pub(crate) install_path: String,
+    /// Every installed mod, including disabled ones
     pub(crate) enabled_mods: Vec<String>,
     pub(crate) game: String,
+    /// How mod files are put in the game directory
+    #[serde(default)]
+    pub(crate) deploy_method: DeployMethod,
+    /// Where plugins.txt and loadorder.txt are written. Empty means the profile directory.
+    #[serde(default)]
+    pub(crate) plugins_directory: String,
+    /// LOOT masterlist that `plugins sort` reads rules from. Empty means none.
+    #[serde(default)]
+    pub(crate) masterlist: String,
+    /// Mods that were only installed to satisfy another mod's dependencies
+    #[serde(default)]
+    pub(crate) dependency_mods: Vec<String>,
+    /// Installed mods from lowest to highest priority. The highest priority copy of a file
+    /// is the one in Data.
+    #[serde(default)]
+    pub(crate) mod_order: Vec<String>,
+    /// Installed mods whose files are kept out of the game directory
+    #[serde(default)]
+    pub(crate) disabled_mods: Vec<String>,
+    /// Plugins in the order the game loads them
+    #[serde(default)]
+    pub(crate) load_order: Vec<String>,
+    /// Plugins left out of plugins.txt
+    #[serde(default)]
+    pub(crate) inactive_plugins: Vec<String>,
+    /// Dependencies of each installed mod at the time it was installed
+    #[serde(default)]
+    pub(crate) mod_depends: HashMap<String, Vec<String>>,
+    /// Installed version of each versioned mod
+    #[serde(default)]
+    pub(crate) mod_versions: HashMap<String, String>,
+    /// Plugins that have to load after the listed ones, whatever their masters say
+    #[serde(default)]
+    pub(crate) load_after: HashMap<String, Vec<String>>,
 }
 /// `ProfileFile` implements `Default`
 impl std::default::Default for ProfileFile {
@@ -35,12 +74,29 @@ impl std::default::Default for ProfileFile {
         install_path: "path/to/fallout/install/".into(),
         enabled_mods: Vec::new(),
         game: "Fallout: New Vegas".into(),
+        deploy_method: DeployMethod::Copy,
+        plugins_directory: String::new(),
+        masterlist: String::new(),
+        dependency_mods: Vec::new(),
+        mod_order: Vec::new(),
+        disabled_mods: Vec::new(),
+        load_order: Vec::new(),
+        inactive_plugins: Vec::new(),
+        mod_depends: HashMap::new(),
+        mod_versions: HashMap::new(),
+        load_after: HashMap::new(),
     }}
 }
 
 pub(crate) fn load_profile_file(profile_path: &str) -> Result<ProfileFile, Box<dyn Error>> {
     // Parse JSON from file
-    let profile:ProfileFile = confy::load_path(profile_path)?;
+    let mut profile:ProfileFile = confy::load_path(profile_path)?;
+    // Mods installed before priorities existed keep their install order
+    for mod_name in &profile.enabled_mods {
+        if !profile.mod_order.contains(mod_name) {
+            profile.mod_order.push(mod_name.clone());
+        }
+    }
     // Return config
     Ok(profile)
 }
@@ -56,6 +112,106 @@ pub(crate) fn save_profile_file(config_path: &str, new_profile: ProfileFile) ->
     };
 }
 
+impl ProfileFile {
+    /// Installed mods that depend on the given mod
+    pub(crate) fn reverse_depends(&self, mod_value: &str) -> Vec<String> {
+        self.enabled_mods.iter()
+            .filter(|other| match self.mod_depends.get(*other) {
+                Some(depends) => depends.iter().any(|item| match versions::parse_spec(item) {
+                    Ok(spec) => spec.name == mod_value,
+                    Err(_) => false
+                }),
+                None => false
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forget everything about an installed mod
+    pub(crate) fn remove_mod(&mut self, mod_name: &str) {
+        self.enabled_mods.retain(|item| item != mod_name);
+        self.dependency_mods.retain(|item| item != mod_name);
+        self.mod_order.retain(|item| item != mod_name);
+        self.disabled_mods.retain(|item| item != mod_name);
+        self.mod_depends.remove(mod_name);
+        self.mod_versions.remove(mod_name);
+    }
+
+    /// Whether an installed mod's files belong in the game directory
+    pub(crate) fn is_enabled(&self, mod_name: &str) -> bool {
+        !self.disabled_mods.iter().any(|item| item == mod_name)
+    }
+
+    /// Whether a plugin is listed in plugins.txt
+    pub(crate) fn is_plugin_active(&self, plugin: &str) -> bool {
+        !self.inactive_plugins.iter().any(|item| item == plugin)
+    }
+
+    /// Position of an installed mod in the priority order, where 1 is the lowest priority
+    pub(crate) fn priority(&self, mod_name: &str) -> Option<usize> {
+        self.mod_order.iter().position(|item| item == mod_name).map(|position| position + 1)
+    }
+
+    /// Give an installed mod a new priority, shifting the mods in between
+    pub(crate) fn set_priority(&mut self, mod_name: &str, priority: usize) -> Result<(), String> {
+        let position = match self.priority(mod_name) {
+            Some(current) => current - 1,
+            None => return Err(format!("{} is not installed", mod_name))
+        };
+        if priority == 0 || priority > self.mod_order.len() {
+            return Err(format!("Priority must be between 1 and {}", self.mod_order.len()));
+        }
+        let item = self.mod_order.remove(position);
+        self.mod_order.insert(priority - 1, item);
+        Ok(())
+    }
+
+    /// The installed mod value of a mod, including its version if it has one
+    pub(crate) fn installed_value(&self, mod_name: &str) -> String {
+        match self.mod_versions.get(mod_name) {
+            Some(version) => format!("{}@{}", mod_name, version),
+            None => mod_name.to_string()
+        }
+    }
+
+    /// Installed mods along with the version of each
+    pub(crate) fn installed_versions(&self) -> HashMap<String, Option<Version>> {
+        self.enabled_mods.iter()
+            .map(|mod_name| (mod_name.clone(), self.mod_versions.get(mod_name).and_then(|version| Version::parse(version).ok())))
+            .collect()
+    }
+}
+
+/// Print the installed mods of a profile along with why they were installed
+pub(crate) fn list_mods(profile: &ProfileFile) {
+    if profile.enabled_mods.is_empty() {
+        println!("No mods installed.");
+        return;
+    }
+    println!("Installed mods:");
+    for mod_value in &profile.enabled_mods {
+        let reason = if profile.dependency_mods.contains(mod_value) { "dependency" } else { "explicit" };
+        let state = if profile.is_enabled(mod_value) { "" } else { ", disabled" };
+        println!("{} [{}{}]", profile.installed_value(mod_value), reason, state);
+        let required_by = profile.reverse_depends(mod_value);
+        if !required_by.is_empty() {
+            println!("    Required by: {}", required_by.join(", "));
+        }
+    }
+}
+
+/// Print the installed mods from highest to lowest priority
+pub(crate) fn list_order(profile: &ProfileFile) {
+    if profile.mod_order.is_empty() {
+        println!("No mods installed.");
+        return;
+    }
+    println!("Mods by priority, highest first:");
+    for (position, mod_name) in profile.mod_order.iter().enumerate().rev() {
+        let state = if profile.is_enabled(mod_name) { "" } else { " [disabled]" };
+        println!("{:>4} {}{}", position + 1, profile.installed_value(mod_name), state);
+    }
+}
 
 pub(crate) fn list_profiles(config_path: &str) -> Result<(), String> {
     let current_profile = match config_file::current_profile(&config_path) {
@@ -0,0 +1,110 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use super::plugins;
+use super::profile::ProfileFile;
+use super::tes4;
+
+/// Record flag marking a record as deleted
+const DELETED_FLAG: u32 = 0x20;
+
+/// A record, identified the same way whichever plugin it is read from
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct RecordId {
+    /// Position of the plugin that defined the record among the active plugins, if it is active
+    origin_index: Option<usize>,
+    origin: String,
+    /// FormID without the load order index
+    object_id: u32,
+}
+
+/// Every active plugin's version of a record, in load order
+struct Edits {
+    kind: [u8; 4],
+    /// Each plugin that has the record and whether it deletes it
+    plugins: Vec<(String, bool)>,
+}
+
+/// Map every record in the active plugins to the plugins that have it
+fn map_records(profile: &ProfileFile) -> Result<BTreeMap<RecordId, Edits>, String> {
+    let on_disk = plugins::plugins_on_disk(&profile.install_path);
+    let active: Vec<&String> = profile.load_order.iter()
+        .filter(|plugin| on_disk.contains(plugin) && profile.is_plugin_active(plugin))
+        .collect();
+    let mut records: BTreeMap<RecordId, Edits> = BTreeMap::new();
+    for plugin in &active {
+        let (header, plugin_records) = match tes4::read_records(&format!("{}/Data/{}", profile.install_path, plugin), |_| false) {
+            Ok(read) => read,
+            Err(issue) => return Err(format!("Failed to read records of {} <- {}", plugin, issue))
+        };
+        for record in plugin_records {
+            let (origin, object_id) = header.origin(plugin, record.form_id);
+            let origin_index = active.iter().position(|item| item.eq_ignore_ascii_case(origin));
+            let origin = match origin_index {
+                Some(index) => active[index].to_string(),
+                None => origin.to_string()
+            };
+            records.entry(RecordId { origin_index, origin, object_id })
+                .or_insert_with(|| Edits { kind: record.kind, plugins: Vec::new() })
+                .plugins.push((plugin.to_string(), record.flags & DELETED_FLAG != 0));
+        }
+    }
+    Ok(records)
+}
+
+/// Print every record that more than one plugin overrides, grouped by the plugins
+/// involved, along with the plugin that wins under the current load order
+///
+/// With `all`, records only one plugin overrides are listed too. With `only`, just the
+/// records that plugin has are listed. Returns whether any conflicts were found.
+pub(crate) fn show_record_conflicts(profile: &ProfileFile, only: Option<&str>, all: bool) -> Result<bool, String> {
+    let records = map_records(profile)?;
+    let mut groups: BTreeMap<Vec<String>, Vec<(RecordId, Edits)>> = BTreeMap::new();
+    for (id, edits) in records {
+        let overrides = edits.plugins.iter().filter(|(plugin, _)| *plugin != id.origin).count();
+        if overrides < if all { 1 } else { 2 } {
+            continue;
+        }
+        if let Some(only) = only {
+            if !edits.plugins.iter().any(|(plugin, _)| plugin.eq_ignore_ascii_case(only)) {
+                continue;
+            }
+        }
+        let involved: Vec<String> = edits.plugins.iter().map(|(plugin, _)| plugin.clone()).collect();
+        groups.entry(involved).or_default().push((id, edits));
+    }
+    if groups.is_empty() {
+        println!("No record conflicts.");
+        return Ok(false);
+    }
+    for (involved, conflicts) in &groups {
+        println!("{} ({} record{})", involved.join(" <-> "), conflicts.len(), if conflicts.len() == 1 { "" } else { "s" });
+        for (id, edits) in conflicts {
+            // Records of inactive masters have no load order index
+            let form_id = match id.origin_index {
+                Some(index) => format!("{:02X}{:06X}", index, id.object_id),
+                None => format!("??{:06X}", id.object_id)
+            };
+            let (winner, deleted) = edits.plugins.last().unwrap();
+            println!("    {} {} from {}: {} wins{}", String::from_utf8_lossy(&edits.kind), form_id, id.origin, winner,
+                if *deleted { ", deleting it" } else { "" });
+        }
+    }
+    Ok(true)
+}
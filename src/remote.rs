@@ -16,57 +16,329 @@
 // along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
 
 use reqwest;
+use semver::Version;
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
 
 use super::config_file;
+use super::digest;
+use super::mods;
+use super::trust;
+use super::versions;
 
-/// Get the package index of a remote repository
-pub(crate) fn get_index(remote: &str) {
-    let index_path = format!("Updated index for: https://{}/index.json", remote);
-    println!("{}", &index_path);
+/// A single mod as it is described by a repository index
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct IndexEntry {
+    pub(crate) author: String,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) version: String,
+    #[serde(default)]
+    pub(crate) description: String,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    #[serde(default)]
+    pub(crate) size: u64,
+    #[serde(default)]
+    pub(crate) depends: Vec<String>,
+    /// Hex encoded SHA-256 digest of the mod's tarball
+    #[serde(default)]
+    pub(crate) sha256: String,
+}
+
+/// The package index served by a repository at `https://<remote>/index.json`
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RepositoryIndex {
+    pub(crate) mods: Vec<IndexEntry>,
+}
+
+impl IndexEntry {
+    /// The `author/mod` string used to refer to this mod
+    pub(crate) fn mod_value(&self) -> String {
+        format!("{}/{}", self.author, self.name)
+    }
+
+    /// The version of this entry, or `None` if the repository doesn't version the mod
+    pub(crate) fn parsed_version(&self) -> Option<Version> {
+        Version::parse(&self.version).ok()
+    }
+
+    /// The `author/mod@version` string referring to exactly this entry
+    pub(crate) fn versioned_value(&self) -> String {
+        versions::versioned_value(&self.mod_value(), &self.parsed_version())
+    }
+}
+
+impl RepositoryIndex {
+    /// Ensure every entry can safely be used as a mod value and as a path
+    fn validate(&self) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for entry in &self.mods {
+            for part in [&entry.author, &entry.name] {
+                if part.is_empty() || part == "." || part == ".."
+                    || part.contains('/') || part.contains('\\') || part.contains(char::is_whitespace) {
+                    return Err(format!("Invalid mod name \"{}/{}\"", entry.author, entry.name));
+                }
+            }
+            if !entry.version.is_empty() {
+                if let Err(issue) = Version::parse(&entry.version) {
+                    return Err(format!("Invalid version {} for {} <- {}", entry.version, entry.mod_value(), issue));
+                }
+            }
+            if !entry.sha256.is_empty() && !digest::is_sha256(&entry.sha256) {
+                return Err(format!("Invalid checksum for {}", entry.mod_value()));
+            }
+            for dependency in &entry.depends {
+                if let Err(issue) = versions::parse_spec(dependency) {
+                    return Err(format!("Invalid dependency of {} <- {}", entry.mod_value(), issue));
+                }
+            }
+            if !seen.insert((entry.mod_value(), entry.version.clone())) {
+                return Err(format!("Duplicate entry for {}", entry.mod_value()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path to the locally cached copy of a repository's index
+pub(crate) fn repository_index_path(config_path: &str, remote: &str) -> String {
+    format!("{}/repositories/{}/index.json", config_path, remote.replace('/', "_"))
+}
+
+/// Parse and validate the JSON body of a repository index
+fn parse_index(body: &str) -> Result<RepositoryIndex, String> {
+    let index: RepositoryIndex = match serde_json::from_str(body) {
+        Ok(index) => index,
+        Err(issue) => return Err(format!("Malformed repository index <- {}", issue))
+    };
+    match index.validate() {
+        Ok(_) => Ok(index),
+        Err(issue) => Err(format!("Invalid repository index <- {}", issue))
+    }
+}
+
+/// Download a file, failing on anything but a successful response
+fn download(url: &str) -> Result<Vec<u8>, String> {
+    let res = match reqwest::blocking::get(url) {
+        Ok(res) => res,
+        Err(issue) => return Err(format!("Failed to reach {} <- {}", url, issue))
+    };
+    if !res.status().is_success() {
+        return Err(format!("{} responded with {}", url, res.status()));
+    }
+    match res.bytes() {
+        Ok(bytes) => Ok(bytes.to_vec()),
+        Err(issue) => Err(format!("Failed to download {} <- {}", url, issue))
+    }
+}
+
+/// Get the package index of a remote repository and cache it under the config directory
+///
+/// The index is signed by the repository at `index.json.sig`. Indices of trusted repositories
+/// are only cached if the signature verifies.
+pub(crate) fn get_index(config_path: &str, remote: &str) -> Result<(), String> {
+    let body = download(&format!("https://{}/index.json", remote))?;
+    let body = match String::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return Err(format!("Index from {} is not valid UTF-8", remote))
+    };
+    // Refuse to replace the cached index with one that doesn't match the schema
+    parse_index(&body)?;
+    let signature = download(&format!("https://{}/index.json.sig", remote))
+        .map(|signature| String::from_utf8_lossy(&signature).trim().to_string());
+    let trusted = trust::load_trust_store(config_path)?.contains_key(remote);
+    if trusted {
+        let signature = match &signature {
+            Ok(signature) => signature,
+            Err(issue) => return Err(format!("Failed to get index signature <- {}", issue))
+        };
+        trust::verify_index(config_path, remote, body.as_bytes(), signature)?;
+    } else {
+        println!("{} is not trusted. Mods can't be installed from it until you run: pipboy repo trust {} <key>", remote, remote);
+    }
+    let index_path = repository_index_path(config_path, remote);
+    let index_directory = Path::new(&index_path).parent().unwrap();
+    if !index_directory.exists() {
+        match fs::create_dir_all(index_directory) {
+            Ok(_) => {}
+            Err(_) => return Err(format!("Failed to create path {}", index_directory.display()))
+        };
+    }
+    let signature_path = format!("{}.sig", index_path);
+    let written = match signature {
+        Ok(signature) => fs::write(&signature_path, signature),
+        // Don't keep a signature that belongs to an older index
+        Err(_) if Path::new(&signature_path).exists() => fs::remove_file(&signature_path),
+        Err(_) => Ok(())
+    };
+    if written.is_err() {
+        return Err(format!("Failed to write index signature for {} to disk", remote));
+    }
+    match fs::write(&index_path, &body) {
+        Ok(_) => { println!("Updated index for {}", remote); Ok(()) },
+        Err(_) => Err(format!("Failed to write index for {} to disk", remote))
+    }
+}
+
+/// Read the locally cached index of a repository exactly as it was served
+fn read_cached_index(config_path: &str, remote: &str) -> Result<String, String> {
+    let index_path = repository_index_path(config_path, remote);
+    if !Path::new(&index_path).exists() {
+        return Err(format!("No index cached for {}. Try updating with -u", remote));
+    }
+    match fs::read_to_string(&index_path) {
+        Ok(body) => Ok(body),
+        Err(_) => Err(format!("Failed to read {}", &index_path))
+    }
+}
+
+/// Load the locally cached index of a repository for browsing
+///
+/// The signature isn't checked, so use `load_trusted_index` for anything that is going to be
+/// installed.
+pub(crate) fn load_repository_index(config_path: &str, remote: &str) -> Result<RepositoryIndex, String> {
+    parse_index(&read_cached_index(config_path, remote)?)
+}
+
+/// Load the locally cached index of a repository to install from
+///
+/// Fails unless the repository is trusted and the cached signature verifies, so nothing
+/// from an unsigned or tampered index is ever installed.
+pub(crate) fn load_trusted_index(config_path: &str, remote: &str) -> Result<RepositoryIndex, String> {
+    let body = read_cached_index(config_path, remote)?;
+    let signature = match fs::read_to_string(format!("{}.sig", repository_index_path(config_path, remote))) {
+        Ok(signature) => signature,
+        Err(_) => return Err(format!("The index of {} is not signed", remote))
+    };
+    trust::verify_index(config_path, remote, body.as_bytes(), &signature)?;
+    parse_index(&body)
+}
+
+/// Check a downloaded tarball against the size and digest its repository index lists
+pub(crate) fn verify_mod(entry: &IndexEntry, bytes: &[u8]) -> Result<(), String> {
+    if entry.size > 0 && bytes.len() as u64 != entry.size {
+        return Err(format!("Expected {} bytes but got {}", entry.size, bytes.len()));
+    }
+    let actual = digest::sha256_bytes(bytes);
+    if actual != entry.sha256.to_lowercase() {
+        return Err(format!("Checksum mismatch, expected {} but got {}", entry.sha256, actual));
+    }
+    Ok(())
 }
 
 pub(crate) fn fetch_mod(config_path: &str, mod_value: &str) -> Result<(), String> {
     // Get remotes from config file
-    let remotes = match config_file::load_config_file(&config_path) {
+    let remotes = match config_file::load_config_file(config_path) {
         Ok(config) => config.repository_list,
         Err(_) => return Err("Failed to load configuration file!".to_string())
     };
+    // Only download mods that can be verified against a repository index
+    let (server, entry) = match find_exact_entry(config_path, &remotes, mod_value) {
+        Some((server, entry)) if !entry.sha256.is_empty() => (server, entry),
+        Some(_) => return Err(format!("The repository index does not list a checksum for {}", mod_value)),
+        None => return Err(format!("{} is not listed in the cached index of any trusted repository. Try updating with -u", mod_value))
+    };
+    let mod_directory = mods::mod_directory(mod_value);
+    let bytes = download(&format!("https://{}/mods/{}/mod.tar.gz", &server, &mod_directory))?;
+    println!("{} was found at {}", &mod_value, &server);
+    // Never let a bad download into the mod cache
+    if let Err(issue) = verify_mod(&entry, &bytes) {
+        return Err(format!("Download of {} failed verification <- {}", mod_value, issue));
+    }
+    let path = format!("{}/mods/cached/{}/", &config_path, &mod_directory);
+    if !Path::new(&path).exists() {
+        match fs::create_dir_all(&path) {
+            Ok(_) => {}
+            Err(_) => return Err(format!("Failed to create path {}", &path))
+        };
+    }
+    let tarball_path = format!("{}/mod.tar.gz", &path);
+    if fs::write(&tarball_path, bytes).is_err() {
+        return Err("Failed to write mod file to disk".to_string());
+    }
+    // Keep the verified digest so later corruption of the cached copy can be noticed
+    match fs::write(mods::cached_digest_path(&tarball_path), format!("{}\n", entry.sha256.to_lowercase())) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Failed to write mod digest to disk".to_string())
+    }
+}
+
+fn collect_versions(config_path: &str, remotes: &[String], mod_name: &str,
+    load: fn(&str, &str) -> Result<RepositoryIndex, String>) -> Vec<(String, IndexEntry)> {
+    let mut return_vector = Vec::new();
     for server in remotes {
-        let url = format!("https://{}/mods/{}/mod.tar.gz", &server, &mod_value);
-        let res = reqwest::blocking::get(&url).unwrap();
-        if res.status().is_success() {
-            // The mod was found. Download the mod.
-            println!("{} was found at {}", &mod_value, &server);
-            let path = format!("{}/mods/cached/{}/", &config_path, &mod_value);
-            if !Path::new(&path).exists() {
-                match fs::create_dir_all(&path) {
-                    Ok(_) => {}
-                    Err(_) => return Err(format!("Failed to create path {}", &path))
-                };
+        if let Ok(index) = load(config_path, server) {
+            for entry in index.mods {
+                if entry.mod_value() == mod_name {
+                    return_vector.push((server.to_string(), entry));
+                }
             }
-            return match fs::write(&format!("{}/mod.tar.gz", &path), res.bytes().unwrap()) {
-                Ok(_) => Ok(()),
-                Err(_) => Err("Failed to write mod file to disk".to_string())
-            };
         }
     }
-    Err("Mod was not found in any repositories".to_string())
+    return_vector
+}
+
+/// Every version of a mod listed by the cached repository indices
+pub(crate) fn find_mod_versions(config_path: &str, remotes: &[String], mod_name: &str) -> Vec<(String, IndexEntry)> {
+    collect_versions(config_path, remotes, mod_name, load_repository_index)
+}
+
+/// Every version of a mod listed by the cached indices of trusted repositories whose
+/// signatures verify
+pub(crate) fn find_installable_versions(config_path: &str, remotes: &[String], mod_name: &str) -> Vec<(String, IndexEntry)> {
+    collect_versions(config_path, remotes, mod_name, load_trusted_index)
+}
+
+/// Find the entry of a trusted repository for exactly the version a mod value names
+pub(crate) fn find_exact_entry(config_path: &str, remotes: &[String], mod_value: &str) -> Option<(String, IndexEntry)> {
+    let version = mods::mod_version(mod_value).and_then(|version| Version::parse(version).ok());
+    find_installable_versions(config_path, remotes, mods::mod_name(mod_value)).into_iter()
+        .find(|(_, entry)| entry.parsed_version() == version)
+}
+
+/// Find the repository entry for a mod value. Without a version the newest version is used.
+pub(crate) fn find_index_entry(config_path: &str, remotes: &[String], mod_value: &str) -> Option<(String, IndexEntry)> {
+    let candidates = find_mod_versions(config_path, remotes, mods::mod_name(mod_value));
+    match mods::mod_version(mod_value) {
+        Some(version) => {
+            let version = Version::parse(version).ok();
+            candidates.into_iter().find(|(_, entry)| entry.parsed_version() == version)
+        }
+        // Earlier repositories win ties, so only replace on a strictly newer version
+        None => candidates.into_iter().fold(None, |best: Option<(String, IndexEntry)>, candidate| match best {
+            Some(best) if best.1.parsed_version() >= candidate.1.parsed_version() => Some(best),
+            _ => Some(candidate)
+        })
+    }
 }
 
-pub(crate) fn fetch_mod_depends(_config_path: &str, remotes: &Vec<String>, mod_value: &str) -> Vec<String> {
+pub(crate) fn fetch_mod_depends(config_path: &str, remotes: &[String], mod_value: &str) -> Result<Vec<String>, String> {
     // TODO: Use game name from profile from remote
+    // Prefer the dependency list from a cached repository index
+    if let Some((_, entry)) = find_index_entry(config_path, remotes, mod_value) {
+        return Ok(entry.depends);
+    }
     let mut return_vector = Vec::new();
     for server in remotes {
-        let url = format!("https://{}/mods/{}/depends.txt", &server, &mod_value);
-        let res = reqwest::blocking::get(&url).unwrap();
+        let url = format!("https://{}/mods/{}/depends.txt", &server, mods::mod_directory(mod_value));
+        let res = match reqwest::blocking::get(&url) {
+            Ok(res) => res,
+            Err(issue) => return Err(format!("Failed to fetch {} <- {}", url, issue))
+        };
         if res.status().is_success() {
-            let body = res.text().unwrap();
+            let body = match res.text() {
+                Ok(body) => body,
+                Err(issue) => return Err(format!("Failed to read {} <- {}", url, issue))
+            };
             for dependency in body.lines() {
-                return_vector.push(dependency.to_string());
+                if !dependency.trim().is_empty() {
+                    return_vector.push(dependency.trim().to_string());
+                }
             }
         }
     }
-    return return_vector;
+    Ok(return_vector)
 }
\ No newline at end of file
@@ -0,0 +1,441 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use semver::{Version, VersionReq};
+use std::collections::{HashMap, HashSet};
+
+use super::mods;
+use super::profile::ProfileFile;
+use super::remote;
+use super::versions;
+use super::versions::ModSpec;
+
+/// Give up if the versions chosen keep getting invalidated by later constraints
+const MAX_ATTEMPTS: usize = 64;
+/// What constraints from the mods the user asked for are attributed to
+const REQUESTED: &str = "the command line";
+
+/// One installable version of a mod
+pub(crate) struct Candidate {
+    /// `None` for mods that no repository index lists a version for
+    pub(crate) version: Option<Version>,
+    /// Dependency declarations of this version
+    pub(crate) depends: Vec<String>,
+}
+
+/// An ordered set of mods to install
+pub(crate) struct InstallPlan {
+    /// Mod values pinned to one version, with every dependency ahead of the mods that need it
+    pub(crate) mods: Vec<String>,
+    /// Dependency declarations of every mod in the plan
+    pub(crate) depends: HashMap<String, Vec<String>>,
+    /// Requested mods that were already installed
+    pub(crate) already_installed: Vec<String>,
+}
+
+/// Depth first walk of the dependency graph that emits mods in post-order
+struct Resolver<'a, F: FnMut(&str) -> Vec<Candidate>> {
+    installed: &'a HashMap<String, Option<Version>>,
+    fetch_candidates: F,
+    candidates: HashMap<String, Vec<Candidate>>,
+    constraints: HashMap<String, Vec<(VersionReq, String)>>,
+    chosen: HashMap<String, Option<Version>>,
+    finished: HashSet<String>,
+    path: Vec<String>,
+    plan: InstallPlan,
+    restart: bool,
+}
+
+impl<'a, F: FnMut(&str) -> Vec<Candidate>> Resolver<'a, F> {
+    fn constrain(&mut self, spec: &ModSpec, required_by: &str) {
+        let constraints = self.constraints.entry(spec.name.clone()).or_default();
+        if !constraints.iter().any(|(requirement, by)| *requirement == spec.requirement && by == required_by) {
+            constraints.push((spec.requirement.clone(), required_by.to_string()));
+        }
+    }
+
+    /// Keep only the constraints placed by the command line and the versions chosen in the
+    /// attempt that just finished, so mods that dropped out of the graph stop constraining it
+    fn forget_unchosen(&mut self) {
+        let chosen: HashSet<String> = self.chosen.iter()
+            .map(|(name, version)| versions::versioned_value(name, version))
+            .collect();
+        for constraints in self.constraints.values_mut() {
+            constraints.retain(|(_, by)| by == REQUESTED || chosen.contains(by));
+        }
+    }
+
+    /// Whether a constraint was placed by the command line or a version chosen in this attempt
+    ///
+    /// Other constraints were learned in earlier attempts and may come from versions that
+    /// are not going to be chosen again.
+    fn is_live(&self, required_by: &str) -> bool {
+        required_by == REQUESTED || match self.chosen.get(mods::mod_name(required_by)) {
+            Some(version) => versions::versioned_value(mods::mod_name(required_by), version) == required_by,
+            None => false
+        }
+    }
+
+    fn describe_constraints(&self, name: &str) -> String {
+        self.constraints[name].iter()
+            .filter(|(_, by)| self.is_live(by))
+            .map(|(requirement, by)| format!("{} requires {}", by, requirement))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// The newest candidate that meets the constraints, or only the live ones
+    fn newest_meeting(&self, name: &str, live_only: bool) -> Option<usize> {
+        let constraints: Vec<&VersionReq> = self.constraints[name].iter()
+            .filter(|(_, by)| !live_only || self.is_live(by))
+            .map(|(requirement, _)| requirement)
+            .collect();
+        let mut best: Option<usize> = None;
+        for (position, candidate) in self.candidates[name].iter().enumerate() {
+            if !constraints.iter().all(|requirement| versions::satisfies(&candidate.version, requirement)) {
+                continue;
+            }
+            match best {
+                Some(best_position) if self.candidates[name][best_position].version >= candidate.version => {}
+                _ => best = Some(position)
+            }
+        }
+        best
+    }
+
+    /// Pick the newest candidate that meets every known constraint
+    ///
+    /// Constraints learned in earlier attempts are dropped if they can't all be met, as the
+    /// versions that placed them may not be chosen this time.
+    fn choose(&mut self, name: &str) -> Result<usize, String> {
+        if !self.candidates.contains_key(name) {
+            let candidates = (self.fetch_candidates)(name);
+            self.candidates.insert(name.to_string(), candidates);
+        }
+        if self.candidates[name].is_empty() {
+            return Err(format!("{} is not listed in any usable repository index. Try updating with -u", name));
+        }
+        match self.newest_meeting(name, false).or_else(|| self.newest_meeting(name, true)) {
+            Some(position) => Ok(position),
+            None => Err(self.unsatisfiable(name))
+        }
+    }
+
+    fn unsatisfiable(&self, name: &str) -> String {
+        format!("No available version of {} satisfies every requirement ({})", name, self.describe_constraints(name))
+    }
+
+    fn visit(&mut self, spec: &ModSpec, required_by: &str) -> Result<(), String> {
+        self.constrain(spec, required_by);
+        if let Some(version) = self.installed.get(&spec.name) {
+            if !versions::satisfies(version, &spec.requirement) {
+                let installed = versions::versioned_value(&spec.name, version);
+                return Err(format!("{} is installed but {} requires {}", installed, required_by, spec.requirement));
+            }
+            return Ok(());
+        }
+        // Reaching a mod that is still on the path means the graph loops back on itself
+        if let Some(position) = self.path.iter().position(|item| *item == spec.name) {
+            let mut cycle = self.path[position..].to_vec();
+            cycle.push(spec.name.clone());
+            return Err(format!("Dependency cycle detected: {}", cycle.join(" -> ")));
+        }
+        if let Some(version) = self.chosen.get(&spec.name) {
+            // A version chosen earlier doesn't fit this requirement, so start over knowing about it
+            if !versions::satisfies(version, &spec.requirement) {
+                if self.newest_meeting(&spec.name, true).is_none() {
+                    return Err(self.unsatisfiable(&spec.name));
+                }
+                self.restart = true;
+            }
+            return Ok(());
+        }
+        let position = self.choose(&spec.name)?;
+        let version = self.candidates[&spec.name][position].version.clone();
+        let depends = self.candidates[&spec.name][position].depends.clone();
+        let mod_value = versions::versioned_value(&spec.name, &version);
+        self.chosen.insert(spec.name.clone(), version);
+        self.path.push(spec.name.clone());
+        for dependency in &depends {
+            let dependency_spec = match versions::parse_spec(dependency) {
+                Ok(dependency_spec) => dependency_spec,
+                Err(issue) => return Err(format!("Invalid dependency of {} <- {}", mod_value, issue))
+            };
+            self.visit(&dependency_spec, &mod_value)?;
+        }
+        self.path.pop();
+        self.finished.insert(spec.name.clone());
+        self.plan.mods.push(mod_value.clone());
+        self.plan.depends.insert(mod_value, depends);
+        Ok(())
+    }
+}
+
+/// Build the full dependency graph of the requested mods and order it for installation
+/// # Arguments
+/// 1. requested - Mods the user asked for
+/// 2. installed - Versions of the mods that are already installed and can be skipped
+/// 3. fetch_candidates - Returns every installable version of a mod
+pub(crate) fn resolve<F: FnMut(&str) -> Vec<Candidate>>(requested: &[ModSpec], installed: &HashMap<String, Option<Version>>, fetch_candidates: F) -> Result<InstallPlan, String> {
+    let mut resolver = Resolver {
+        installed,
+        fetch_candidates,
+        candidates: HashMap::new(),
+        constraints: HashMap::new(),
+        chosen: HashMap::new(),
+        finished: HashSet::new(),
+        path: Vec::new(),
+        plan: InstallPlan { mods: Vec::new(), depends: HashMap::new(), already_installed: Vec::new() },
+        restart: false,
+    };
+    for attempt in 0..MAX_ATTEMPTS {
+        // Constraints are kept between attempts so every retry knows more than the last one
+        if attempt > 0 {
+            resolver.forget_unchosen();
+        }
+        resolver.chosen.clear();
+        resolver.finished.clear();
+        resolver.path.clear();
+        resolver.plan = InstallPlan { mods: Vec::new(), depends: HashMap::new(), already_installed: Vec::new() };
+        resolver.restart = false;
+        for spec in requested {
+            if installed.contains_key(&spec.name) && !resolver.plan.already_installed.contains(&spec.name) {
+                resolver.plan.already_installed.push(spec.name.clone());
+            }
+            resolver.visit(spec, REQUESTED)?;
+        }
+        if !resolver.restart {
+            return Ok(resolver.plan);
+        }
+    }
+    Err("Unable to find a set of versions that satisfies every dependency".to_string())
+}
+
+/// Versions of a mod according to the cached repository indices
+///
+/// With `trusted_only`, only the indices of trusted repositories whose signatures verify are
+/// used. Only mods an index lists can be verified, so nothing else is a candidate.
+pub(crate) fn repository_candidates(config_path: &str, remotes: &[String], mod_name: &str, trusted_only: bool) -> Vec<Candidate> {
+    let entries = match trusted_only {
+        true => remote::find_installable_versions(config_path, remotes, mod_name),
+        false => remote::find_mod_versions(config_path, remotes, mod_name)
+    };
+    entries.into_iter()
+        .map(|(_, entry)| Candidate { version: entry.parsed_version(), depends: entry.depends })
+        .collect()
+}
+
+/// Print what an install plan is going to do
+pub(crate) fn print_plan(plan: &InstallPlan, requested: &[ModSpec]) {
+    for mod_value in &plan.already_installed {
+        println!("{} is already installed", mod_value);
+    }
+    if plan.mods.is_empty() {
+        println!("Nothing to install.");
+        return;
+    }
+    println!("Installing {} mod(s) in this order:", plan.mods.len());
+    for mod_value in &plan.mods {
+        let name = mods::mod_name(mod_value);
+        if requested.iter().any(|spec| spec.name == name) {
+            println!("    {}", mod_value);
+        } else {
+            // Name what pulled the dependency in
+            let required_by: Vec<&str> = plan.mods.iter()
+                .filter(|other| plan.depends[*other].iter()
+                    .any(|dependency| versions::parse_spec(dependency).map(|spec| spec.name == name).unwrap_or(false)))
+                .map(|other| other.as_str())
+                .collect();
+            println!("    {} (dependency of {})", mod_value, required_by.join(", "));
+        }
+    }
+}
+
+/// Work out which mods to remove when uninstalling the requested ones
+///
+/// Fails if an installed mod depends on one being removed, unless `cascade` is given in which
+/// case everything that depends on a removed mod is removed as well.
+pub(crate) fn plan_removal(profile: &ProfileFile, requested: &[String], cascade: bool) -> Result<Vec<String>, String> {
+    let mut removing: Vec<String> = Vec::new();
+    for mod_name in requested {
+        if !profile.enabled_mods.contains(mod_name) {
+            return Err(format!("{} isn't installed!", mod_name));
+        }
+        if !removing.contains(mod_name) {
+            removing.push(mod_name.clone());
+        }
+    }
+    let mut position = 0;
+    while position < removing.len() {
+        let blockers: Vec<String> = profile.reverse_depends(&removing[position]).into_iter()
+            .filter(|dependent| !removing.contains(dependent))
+            .collect();
+        if !blockers.is_empty() {
+            if !cascade {
+                return Err(format!("{} is required by {}. Use --cascade to remove them as well", removing[position], blockers.join(", ")));
+            }
+            removing.extend(blockers);
+        }
+        position += 1;
+    }
+    Ok(removing)
+}
+
+/// Mods installed only as dependencies that nothing would need after removing the given mods
+pub(crate) fn find_orphans(profile: &ProfileFile, removing: &[String]) -> Vec<String> {
+    let mut gone: Vec<String> = removing.to_vec();
+    let mut orphans = Vec::new();
+    loop {
+        let found: Vec<String> = profile.dependency_mods.iter()
+            .filter(|mod_name| profile.enabled_mods.contains(mod_name) && !gone.contains(mod_name))
+            .filter(|mod_name| profile.reverse_depends(mod_name).iter().all(|dependent| gone.contains(dependent)))
+            .cloned()
+            .collect();
+        if found.is_empty() {
+            return orphans;
+        }
+        gone.extend(found.iter().cloned());
+        orphans.extend(found);
+    }
+}
+
+/// Order mods for removal so that every mod is removed before the mods it depends on
+pub(crate) fn order_removal(profile: &ProfileFile, removing: &[String]) -> Vec<String> {
+    let mut left: Vec<String> = removing.to_vec();
+    let mut ordered = Vec::new();
+    while !left.is_empty() {
+        // Cycles can't be installed, but never loop forever on a hand edited profile
+        let position = left.iter()
+            .position(|mod_name| !profile.reverse_depends(mod_name).iter().any(|dependent| left.contains(dependent)))
+            .unwrap_or(0);
+        ordered.push(left.remove(position));
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolve against a fixed set of mods given as (name, version, dependencies)
+    fn resolve_with(requested: &[&str], available: &[(&str, &str, &[&str])]) -> Result<InstallPlan, String> {
+        let requested: Vec<ModSpec> = requested.iter().map(|spec| versions::parse_spec(spec).unwrap()).collect();
+        resolve(&requested, &HashMap::new(), |name| available.iter()
+            .filter(|(candidate, _, _)| *candidate == name)
+            .map(|(_, version, depends)| Candidate {
+                version: Version::parse(version).ok(),
+                depends: depends.iter().map(|dependency| dependency.to_string()).collect(),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn dependencies_come_before_the_mods_that_need_them() {
+        let plan = resolve_with(&["x/app"], &[
+            ("x/app", "", &["x/lib", "x/ui"]),
+            ("x/ui", "", &["x/lib"]),
+            ("x/lib", "", &[]),
+        ]).unwrap();
+        assert_eq!(plan.mods, vec!["x/lib", "x/ui", "x/app"]);
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_path() {
+        let issue = resolve_with(&["x/a"], &[
+            ("x/a", "", &["x/b"]),
+            ("x/b", "", &["x/c"]),
+            ("x/c", "", &["x/a"]),
+        ]).err().unwrap();
+        assert_eq!(issue, "Dependency cycle detected: x/a -> x/b -> x/c -> x/a");
+    }
+
+    #[test]
+    fn installed_mods_are_not_planned_again() {
+        let requested = vec![versions::parse_spec("x/app").unwrap()];
+        let mut installed = HashMap::new();
+        installed.insert("x/lib".to_string(), None);
+        let plan = resolve(&requested, &installed, |name| match name {
+            "x/app" => vec![Candidate { version: None, depends: vec!["x/lib".to_string()] }],
+            _ => Vec::new()
+        }).unwrap();
+        assert_eq!(plan.mods, vec!["x/app"]);
+    }
+
+    #[test]
+    fn newest_version_meeting_every_constraint_is_chosen() {
+        let plan = resolve_with(&["x/app"], &[
+            ("x/app", "1.0.0", &["x/lib@>=1.1, <2"]),
+            ("x/lib", "1.0.0", &[]),
+            ("x/lib", "1.2.0", &[]),
+            ("x/lib", "1.4.1", &[]),
+            ("x/lib", "2.0.0", &[]),
+        ]).unwrap();
+        assert_eq!(plan.mods, vec!["x/lib@1.4.1", "x/app@1.0.0"]);
+    }
+
+    #[test]
+    fn later_constraints_restart_with_an_older_version() {
+        let plan = resolve_with(&["x/app"], &[
+            ("x/app", "1.0.0", &["x/lib", "x/ui"]),
+            ("x/ui", "1.0.0", &["x/lib@1"]),
+            ("x/lib", "1.0.0", &[]),
+            ("x/lib", "2.0.0", &[]),
+        ]).unwrap();
+        assert_eq!(plan.mods, vec!["x/lib@1.0.0", "x/ui@1.0.0", "x/app@1.0.0"]);
+    }
+
+    #[test]
+    fn conflicting_constraints_name_every_requirement() {
+        let issue = resolve_with(&["x/app"], &[
+            ("x/app", "1.0.0", &["x/lib@1", "x/ui"]),
+            ("x/ui", "1.0.0", &["x/lib@2"]),
+            ("x/lib", "1.0.0", &[]),
+            ("x/lib", "2.0.0", &[]),
+        ]).err().unwrap();
+        assert_eq!(issue, "No available version of x/lib satisfies every requirement (x/app@1.0.0 requires ^1, x/ui@1.0.0 requires ^2)");
+    }
+
+    #[test]
+    fn constraints_of_versions_no_longer_chosen_are_forgotten() {
+        // The first attempt picks x/lib@2, whose requirement on x/core no longer applies once
+        // x/ui forces x/lib@1
+        let plan = resolve_with(&["x/app"], &[
+            ("x/app", "1.0.0", &["x/lib", "x/ui"]),
+            ("x/ui", "1.0.0", &["x/lib@1"]),
+            ("x/lib", "2.0.0", &["x/core@1"]),
+            ("x/lib", "1.0.0", &["x/core@2"]),
+            ("x/core", "1.0.0", &[]),
+            ("x/core", "2.0.0", &[]),
+        ]).unwrap();
+        assert_eq!(plan.mods, vec!["x/core@2.0.0", "x/lib@1.0.0", "x/ui@1.0.0", "x/app@1.0.0"]);
+    }
+
+    #[test]
+    fn constraints_of_mods_that_left_the_graph_are_forgotten() {
+        // x/old is only needed by x/lib@2, which the first attempt picks
+        let plan = resolve_with(&["x/app"], &[
+            ("x/app", "1.0.0", &["x/lib", "x/core", "x/ui"]),
+            ("x/ui", "1.0.0", &["x/lib@1"]),
+            ("x/lib", "2.0.0", &["x/old"]),
+            ("x/lib", "1.0.0", &["x/core@2"]),
+            ("x/old", "1.0.0", &["x/core@1"]),
+            ("x/core", "1.0.0", &[]),
+            ("x/core", "2.0.0", &[]),
+        ]).unwrap();
+        assert_eq!(plan.mods, vec!["x/core@2.0.0", "x/lib@1.0.0", "x/ui@1.0.0", "x/app@1.0.0"]);
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use super::remote;
+use super::remote::IndexEntry;
+
+/// A search hit along with the repository it came from
+struct SearchResult {
+    remote: String,
+    entry: IndexEntry,
+    score: u32,
+}
+
+/// Number of single character edits needed to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Score how well a single field matches a term. Zero means no match.
+fn score_field(field: &str, term: &str) -> u32 {
+    let field = field.to_lowercase();
+    if field == term {
+        return 100;
+    }
+    if field.contains(term) {
+        return 60;
+    }
+    // Allow roughly one typo for every four characters of the search term
+    let tolerance = term.chars().count() / 4;
+    if tolerance > 0 {
+        let words = field.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty());
+        for word in words {
+            if edit_distance(word, term) <= tolerance {
+                return 30;
+            }
+        }
+    }
+    0
+}
+
+/// Score an index entry against every search term. Every term has to match something.
+fn score_entry(entry: &IndexEntry, terms: &[String]) -> u32 {
+    let mut total = 0;
+    for term in terms {
+        // Weight matches on the name above matches on other fields
+        let mut best = score_field(&entry.name, term) * 3;
+        best = best.max(score_field(&entry.author, term) * 2);
+        for tag in &entry.tags {
+            best = best.max(score_field(tag, term) * 2);
+        }
+        best = best.max(score_field(&entry.description, term));
+        if best == 0 {
+            return 0;
+        }
+        total += best;
+    }
+    total
+}
+
+/// Search the locally cached repository indices and print any matching mods
+pub(crate) fn search(config_path: &str, remotes: &[String], query: &[&str]) -> Result<(), String> {
+    let terms: Vec<String> = query.iter().map(|term| term.to_lowercase()).collect();
+    let mut results = Vec::new();
+    let mut searched = 0;
+    for server in remotes {
+        let index = match remote::load_repository_index(config_path, server) {
+            Ok(index) => index,
+            Err(issue) => { println!("Skipping {} <- {}", server, issue); continue }
+        };
+        searched += 1;
+        for entry in index.mods {
+            let score = score_entry(&entry, &terms);
+            if score > 0 {
+                results.push(SearchResult { remote: server.to_string(), entry, score });
+            }
+        }
+    }
+    if searched == 0 {
+        return Err("No cached repository index could be used".to_string());
+    }
+    // Only show the newest version of each mod from each repository
+    results.sort_by_key(|result| Reverse(result.entry.parsed_version()));
+    let mut seen = HashSet::new();
+    results.retain(|result| seen.insert((result.remote.clone(), result.entry.mod_value())));
+    if results.is_empty() {
+        println!("No mods found.");
+        return Ok(());
+    }
+    // Best matches first, then alphabetically
+    results.sort_by(|a, b| b.score.cmp(&a.score)
+        .then_with(|| a.entry.mod_value().cmp(&b.entry.mod_value())));
+    for result in results {
+        let entry = &result.entry;
+        print!("{}", entry.mod_value());
+        if !entry.version.is_empty() {
+            print!(" {}", entry.version);
+        }
+        println!(" [{}]", result.remote);
+        if !entry.description.is_empty() {
+            println!("    {}", entry.description);
+        }
+        if !entry.tags.is_empty() {
+            println!("    Tags: {}", entry.tags.join(", "));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, author: &str, tags: &[&str], description: &str) -> IndexEntry {
+        IndexEntry {
+            author: author.to_string(),
+            name: name.to_string(),
+            version: String::new(),
+            description: description.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            size: 0,
+            depends: Vec::new(),
+            sha256: String::new(),
+        }
+    }
+
+    fn terms(query: &[&str]) -> Vec<String> {
+        query.iter().map(|term| term.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("weapon", "weapon"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("weapn", "weapon"), 1);
+        assert_eq!(edit_distance("waepon", "weapon"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn exact_matches_beat_partial_and_fuzzy_matches() {
+        assert_eq!(score_field("Weapons", "weapons"), 100);
+        assert_eq!(score_field("Weapons Expanded", "weapons"), 60);
+        assert_eq!(score_field("More Weapns", "weapons"), 30);
+        assert_eq!(score_field("Armor", "weapons"), 0);
+    }
+
+    #[test]
+    fn short_terms_allow_no_typos() {
+        assert_eq!(score_field("gun", "gnu"), 0);
+    }
+
+    #[test]
+    fn names_weigh_more_than_other_fields() {
+        let by_name = entry("weapons", "someone", &[], "");
+        let by_tag = entry("arsenal", "someone", &["weapons"], "");
+        let by_description = entry("arsenal", "someone", &[], "adds weapons");
+        let terms = terms(&["weapons"]);
+        assert!(score_entry(&by_name, &terms) > score_entry(&by_tag, &terms));
+        assert!(score_entry(&by_tag, &terms) > score_entry(&by_description, &terms));
+    }
+
+    #[test]
+    fn every_term_has_to_match() {
+        let mod_entry = entry("weapons", "someone", &[], "");
+        assert!(score_entry(&mod_entry, &terms(&["weapons", "someone"])) > 0);
+        assert_eq!(score_entry(&mod_entry, &terms(&["weapons", "armor"])), 0);
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek};
+
+use flate2::read::ZlibDecoder;
+
+/// Size of a record header in Fallout 3 and New Vegas plugins
+const RECORD_HEADER_SIZE: usize = 24;
+/// Size of a subrecord header
+const SUBRECORD_HEADER_SIZE: usize = 6;
+/// Record flag marking a plugin as a master, whatever its extension
+const MASTER_FLAG: u32 = 0x1;
+/// Record flag for data stored zlib compressed, after its decompressed size
+const COMPRESSED_FLAG: u32 = 0x0004_0000;
+/// Form version of records written by the Fallout: New Vegas editor
+const FORM_VERSION: u16 = 15;
+/// Headers are tiny, so anything this big is a corrupt file rather than a plugin
+const MAX_HEADER_SIZE: usize = 16 * 1024 * 1024;
+
+/// The TES4 record every plugin starts with
+pub(crate) struct PluginHeader {
+    pub(crate) flags: u32,
+    /// Number of records and groups in the plugin
+    pub(crate) record_count: u32,
+    pub(crate) author: String,
+    pub(crate) description: String,
+    /// Plugins that have to load before this one, in the order they were listed
+    pub(crate) masters: Vec<String>,
+}
+
+impl PluginHeader {
+    /// Whether the game loads the plugin along with the other masters
+    pub(crate) fn is_master(&self) -> bool {
+        self.flags & MASTER_FLAG != 0
+    }
+
+    /// The plugin that first defined a FormID used by the plugin `name`, and the FormID
+    /// without its load order index
+    ///
+    /// The top byte of a FormID indexes the plugin's masters, with the index after the
+    /// last master meaning the plugin itself.
+    pub(crate) fn origin<'a>(&'a self, name: &'a str, form_id: u32) -> (&'a str, u32) {
+        match self.masters.get((form_id >> 24) as usize) {
+            Some(master) => (master, form_id & 0x00FF_FFFF),
+            None => (name, form_id & 0x00FF_FFFF)
+        }
+    }
+}
+
+/// A record from the groups after the TES4 header
+pub(crate) struct Record {
+    pub(crate) kind: [u8; 4],
+    pub(crate) flags: u32,
+    pub(crate) form_id: u32,
+    /// Decompressed data, only read for the record types that were asked for
+    pub(crate) data: Vec<u8>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Decode a null terminated string. Plugins use Windows-1252, which matches Latin-1 for
+/// nearly every character that shows up in names.
+fn read_zstring(bytes: &[u8]) -> String {
+    bytes.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect()
+}
+
+/// A subrecord's type and data
+type Subrecord<'a> = ([u8; 4], &'a [u8]);
+
+/// Split the data of a record into its subrecords
+///
+/// An XXXX subrecord holds the real size of the subrecord after it, for data too large
+/// for the usual 16 bit size.
+pub(crate) fn subrecords(data: &[u8]) -> Result<Vec<Subrecord<'_>>, String> {
+    let mut subrecords = Vec::new();
+    let mut offset = 0;
+    let mut next_size = None;
+    while offset < data.len() {
+        if offset + SUBRECORD_HEADER_SIZE > data.len() {
+            return Err(format!("Subrecord header at offset {} is cut off", offset));
+        }
+        let kind = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        let size = match next_size.take() {
+            Some(size) => size,
+            None => read_u16(data, offset + 4) as usize
+        };
+        let start = offset + SUBRECORD_HEADER_SIZE;
+        if start + size > data.len() {
+            return Err(format!("{} subrecord at offset {} runs past the end of its record", String::from_utf8_lossy(&kind), offset));
+        }
+        if &kind == b"XXXX" && size == 4 {
+            next_size = Some(read_u32(data, start) as usize);
+        } else {
+            subrecords.push((kind, &data[start..start + size]));
+        }
+        offset = start + size;
+    }
+    Ok(subrecords)
+}
+
+/// Parse the TES4 header at the start of a plugin
+pub(crate) fn parse_header<R: Read + ?Sized>(reader: &mut R) -> Result<PluginHeader, String> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    if let Err(issue) = reader.read_exact(&mut header) {
+        return Err(format!("Failed to read record header <- {}", issue));
+    }
+    if &header[0..4] != b"TES4" {
+        return Err("Not a plugin, the file does not start with a TES4 record".to_string());
+    }
+    let size = read_u32(&header, 4) as usize;
+    if size > MAX_HEADER_SIZE {
+        return Err(format!("TES4 record claims to be {} bytes long", size));
+    }
+    let mut data = vec![0u8; size];
+    if let Err(issue) = reader.read_exact(&mut data) {
+        return Err(format!("Failed to read TES4 record <- {}", issue));
+    }
+    let mut plugin = PluginHeader {
+        flags: read_u32(&header, 8),
+        record_count: 0,
+        author: String::new(),
+        description: String::new(),
+        masters: Vec::new(),
+    };
+    for (kind, data) in subrecords(&data)? {
+        match &kind {
+            // HEDR starts with the format version, which the game doesn't check
+            b"HEDR" if data.len() >= 8 => plugin.record_count = read_u32(data, 4),
+            b"CNAM" => plugin.author = read_zstring(data),
+            b"SNAM" => plugin.description = read_zstring(data),
+            b"MAST" => plugin.masters.push(read_zstring(data)),
+            _ => {}
+        }
+    }
+    Ok(plugin)
+}
+
+/// Read the TES4 header of a plugin file
+pub(crate) fn read_header(path: &str) -> Result<PluginHeader, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(issue) => return Err(format!("Failed to open {} <- {}", path, issue))
+    };
+    parse_header(&mut file)
+}
+
+/// Inflate the data of a compressed record, skipping the decompressed size it starts with
+fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 4 {
+        return Err("Compressed record is missing its decompressed size".to_string());
+    }
+    let mut decompressed = Vec::new();
+    match ZlibDecoder::new(&data[4..]).read_to_end(&mut decompressed) {
+        Ok(_) => Ok(decompressed),
+        Err(issue) => Err(format!("Failed to decompress record <- {}", issue))
+    }
+}
+
+/// Fill `buffer`, or return false if the reader was already at its end
+fn read_or_end<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<bool, String> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err("File ends in the middle of a header".to_string()),
+            Ok(read) => filled += read,
+            Err(issue) if issue.kind() == ErrorKind::Interrupted => {}
+            Err(issue) => return Err(format!("Failed to read header <- {}", issue))
+        }
+    }
+    Ok(true)
+}
+
+/// Read the header and every record of a plugin, along with the data of the record types
+/// `with_data` accepts
+///
+/// Groups only matter for their contents, so they are walked straight through.
+pub(crate) fn read_records(path: &str, with_data: impl Fn(&[u8; 4]) -> bool) -> Result<(PluginHeader, Vec<Record>), String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(issue) => return Err(format!("Failed to open {} <- {}", path, issue))
+    };
+    let file_size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(issue) => return Err(format!("Failed to read size of {} <- {}", path, issue))
+    };
+    let mut reader = BufReader::new(file);
+    let header = parse_header(&mut reader)?;
+    let mut records = Vec::new();
+    let mut buffer = [0u8; RECORD_HEADER_SIZE];
+    // Where the next header starts, so record sizes can be checked against what is left
+    let mut offset = match reader.stream_position() {
+        Ok(offset) => offset,
+        Err(issue) => return Err(format!("Failed to read {} <- {}", path, issue))
+    };
+    while read_or_end(&mut reader, &mut buffer)? {
+        let kind = [buffer[0], buffer[1], buffer[2], buffer[3]];
+        offset += RECORD_HEADER_SIZE as u64;
+        if &kind == b"GRUP" {
+            continue;
+        }
+        let size = read_u32(&buffer, 4);
+        let flags = read_u32(&buffer, 8);
+        if size as u64 > file_size.saturating_sub(offset) {
+            return Err(format!("{} record at offset {} claims to be {} bytes long, past the end of the file",
+                String::from_utf8_lossy(&kind), offset - RECORD_HEADER_SIZE as u64, size));
+        }
+        offset += size as u64;
+        let mut data = Vec::new();
+        if with_data(&kind) {
+            data.resize(size as usize, 0);
+            if let Err(issue) = reader.read_exact(&mut data) {
+                return Err(format!("Failed to read {} record <- {}", String::from_utf8_lossy(&kind), issue));
+            }
+            if flags & COMPRESSED_FLAG != 0 {
+                data = decompress(&data)?;
+            }
+        } else if let Err(issue) = reader.seek_relative(size as i64) {
+            return Err(format!("Failed to skip {} record <- {}", String::from_utf8_lossy(&kind), issue));
+        }
+        records.push(Record { kind, flags, form_id: read_u32(&buffer, 12), data });
+    }
+    Ok((header, records))
+}
+
+/// Append a subrecord, preceded by an XXXX subrecord if it is too large for a 16 bit size
+pub(crate) fn write_subrecord(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let size = match u16::try_from(data.len()) {
+        Ok(size) => size,
+        Err(_) => {
+            out.extend_from_slice(b"XXXX");
+            out.extend_from_slice(&4u16.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            0
+        }
+    };
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Append an uncompressed record
+pub(crate) fn write_record(out: &mut Vec<u8>, kind: &[u8; 4], flags: u32, form_id: u32, data: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(flags & !COMPRESSED_FLAG).to_le_bytes());
+    out.extend_from_slice(&form_id.to_le_bytes());
+    // Version control info, then the form version and an unused field
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&FORM_VERSION.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Append a top level group holding every record of one type
+pub(crate) fn write_group(out: &mut Vec<u8>, kind: &[u8; 4], records: &[u8]) {
+    out.extend_from_slice(b"GRUP");
+    out.extend_from_slice(&((RECORD_HEADER_SIZE + records.len()) as u32).to_le_bytes());
+    out.extend_from_slice(kind);
+    // Group type 0 is a top level group, followed by its stamp and unused fields
+    out.extend_from_slice(&[0u8; 12]);
+    out.extend_from_slice(records);
+}
+
+/// Build the TES4 header of a new plugin
+pub(crate) fn header_bytes(author: &str, description: &str, masters: &[String], record_count: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut hedr = Vec::new();
+    hedr.extend_from_slice(&1.34f32.to_le_bytes());
+    hedr.extend_from_slice(&record_count.to_le_bytes());
+    // Next object ID, unused since the plugin adds no new records
+    hedr.extend_from_slice(&0x800u32.to_le_bytes());
+    write_subrecord(&mut data, b"HEDR", &hedr);
+    write_subrecord(&mut data, b"CNAM", &zstring_bytes(author));
+    write_subrecord(&mut data, b"SNAM", &zstring_bytes(description));
+    for master in masters {
+        write_subrecord(&mut data, b"MAST", &zstring_bytes(master));
+        // Each master is followed by a DATA subrecord the game ignores
+        write_subrecord(&mut data, b"DATA", &[0u8; 8]);
+    }
+    let mut out = Vec::new();
+    write_record(&mut out, b"TES4", 0, 0, &data);
+    out
+}
+
+/// Encode a null terminated string, the reverse of `read_zstring`
+fn zstring_bytes(text: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = text.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect();
+    bytes.push(0);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn headers_round_trip() {
+        let masters = vec!["FalloutNV.esm".to_string(), "DeadMoney.esm".to_string()];
+        let bytes = header_bytes("Someone", "A plugin", &masters, 7);
+        let header = parse_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.author, "Someone");
+        assert_eq!(header.description, "A plugin");
+        assert_eq!(header.masters, masters);
+        assert_eq!(header.record_count, 7);
+        assert!(!header.is_master());
+        assert_eq!(header.origin("Mine.esp", 0x0100_0ABC), ("DeadMoney.esm", 0xABC));
+        assert_eq!(header.origin("Mine.esp", 0x0200_0ABC), ("Mine.esp", 0xABC));
+    }
+
+    #[test]
+    fn other_files_are_not_plugins() {
+        let mut bytes = header_bytes("", "", &[], 0);
+        bytes[0..4].copy_from_slice(b"TES3");
+        assert!(parse_header(&mut bytes.as_slice()).is_err());
+        assert!(parse_header(&mut &b"TES4"[..]).is_err());
+    }
+
+    #[test]
+    fn large_subrecords_are_sized_by_xxxx() {
+        let large = vec![7u8; 70_000];
+        let mut data = Vec::new();
+        write_subrecord(&mut data, b"EDID", b"small\0");
+        write_subrecord(&mut data, b"DATA", &large);
+        write_subrecord(&mut data, b"FULL", b"after\0");
+        let found = subrecords(&data).unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!(&found[1].0, b"DATA");
+        assert_eq!(found[1].1.len(), large.len());
+        assert_eq!(found[2], (*b"FULL", &b"after\0"[..]));
+    }
+
+    #[test]
+    fn cut_off_subrecords_are_refused() {
+        let mut data = Vec::new();
+        write_subrecord(&mut data, b"EDID", b"name\0");
+        assert!(subrecords(&data[..data.len() - 1]).is_err());
+        assert!(subrecords(&data[..3]).is_err());
+    }
+
+    #[test]
+    fn compressed_records_are_inflated() {
+        let mut plain = Vec::new();
+        write_subrecord(&mut plain, b"EDID", b"Leveled\0");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let mut compressed = (plain.len() as u32).to_le_bytes().to_vec();
+        compressed.extend_from_slice(&encoder.finish().unwrap());
+
+        let mut records = Vec::new();
+        write_record(&mut records, b"LVLI", 0, 0x0100_0001, &plain);
+        // write_record never compresses, so lay the compressed record out by hand
+        let mut record = Vec::new();
+        write_record(&mut record, b"LVLI", 0, 0x0100_0002, &compressed);
+        record[8..12].copy_from_slice(&COMPRESSED_FLAG.to_le_bytes());
+        records.extend_from_slice(&record);
+        write_record(&mut records, b"WEAP", 0, 0x0100_0003, b"skipped");
+        let mut bytes = header_bytes("", "", &["FalloutNV.esm".to_string()], 3);
+        write_group(&mut bytes, b"LVLI", &records);
+
+        let path = std::env::temp_dir().join(format!("pipboy-tes4-{}.esp", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let (header, found) = read_records(&path.to_string_lossy(), |kind| kind == b"LVLI").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(header.masters, vec!["FalloutNV.esm"]);
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].data, plain);
+        assert_eq!(found[1].form_id, 0x0100_0002);
+        assert_eq!(found[1].data, plain);
+        assert_eq!(&found[2].kind, b"WEAP");
+        assert!(found[2].data.is_empty());
+    }
+
+    #[test]
+    fn records_larger_than_the_file_are_refused() {
+        let mut bytes = header_bytes("", "", &[], 1);
+        write_record(&mut bytes, b"LVLI", 0, 1, b"data");
+        // The size follows the type at the start of the last record
+        let claimed = bytes.len() - 4 - RECORD_HEADER_SIZE + 4;
+        bytes[claimed..claimed + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let path = std::env::temp_dir().join(format!("pipboy-tes4-size-{}.esp", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let read = read_records(&path.to_string_lossy(), |_| true);
+        fs::remove_file(&path).unwrap();
+        assert!(read.err().unwrap().contains("past the end of the file"));
+    }
+}
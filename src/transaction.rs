@@ -0,0 +1,389 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config_file;
+use super::profile;
+
+/// Records every change made to a profile so that it can be undone as a whole
+///
+/// Overwritten or removed files are copied into `transactions/<profile>/` beforehand and
+/// every change is written to a journal there, so a transaction that was interrupted by a crash
+/// is rolled back the next time one begins.
+pub(crate) struct Transaction {
+    directory: String,
+    config_path: String,
+    install_path: String,
+    profile_path: String,
+    ownership_path: String,
+    created: Vec<String>,
+    backed_up: Vec<String>,
+    /// Symlinks that were about to be replaced, along with their targets
+    symlinks: Vec<(String, String)>,
+    /// Directories extracted from the mod cache, thrown away so they are extracted again
+    discarded: Vec<String>,
+    /// Files whose modification times are about to change, along with their original times
+    modified: Vec<(String, SystemTime)>,
+}
+
+/// Copy a file, creating the destination's parent directories
+pub(crate) fn copy_file(from: &str, to: &str) -> Result<(), String> {
+    if let Some(parent) = Path::new(to).parent() {
+        if fs::create_dir_all(parent).is_err() {
+            return Err(format!("Failed to create path {}", parent.display()));
+        }
+    }
+    match fs::copy(from, to) {
+        Ok(_) => Ok(()),
+        Err(issue) => Err(format!("Failed to copy {} to {} <- {}", from, to, issue))
+    }
+}
+
+/// Create a symbolic link at `link` pointing to `target`
+#[cfg(unix)]
+pub(crate) fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Create a symbolic link at `link` pointing to `target`
+#[cfg(windows)]
+pub(crate) fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+impl Transaction {
+    /// Start a transaction on the current profile, rolling back any interrupted one first
+    pub(crate) fn begin(config_path: &str) -> Result<Transaction, String> {
+        let profile_name = match config_file::current_profile(config_path) {
+            Ok(name) => name,
+            Err(issue) => return Err(format!("Failed to get current profile <- {}", issue))
+        };
+        let profile_directory = format!("{}/profiles/{}", config_path, profile_name);
+        let profile_path = format!("{}/profile", profile_directory);
+        let install_path = match profile::load_profile_file(&profile_path) {
+            Ok(profile) => profile.install_path,
+            Err(issue) => return Err(format!("Failed to load profile <- {}", issue))
+        };
+        let mut transaction = Transaction {
+            directory: format!("{}/transactions/{}", config_path, profile_name),
+            config_path: config_path.to_string(),
+            install_path,
+            profile_path,
+            ownership_path: format!("{}/file_ownership.json", profile_directory),
+            created: Vec::new(),
+            backed_up: Vec::new(),
+            symlinks: Vec::new(),
+            discarded: Vec::new(),
+            modified: Vec::new(),
+        };
+        if Path::new(&transaction.directory).exists() {
+            println!("Rolling back an interrupted transaction.");
+            transaction.load_journal()?;
+            transaction.undo()?;
+        }
+        if fs::create_dir_all(format!("{}/backup", transaction.directory)).is_err() {
+            return Err(format!("Failed to create path {}", transaction.directory));
+        }
+        // Snapshot the state that lives outside of the game directory
+        copy_file(&transaction.profile_path, &format!("{}/profile", transaction.directory))?;
+        if Path::new(&transaction.ownership_path).exists() {
+            copy_file(&transaction.ownership_path, &format!("{}/file_ownership.json", transaction.directory))?;
+        }
+        Ok(transaction)
+    }
+
+    fn load_journal(&mut self) -> Result<(), String> {
+        let journal_path = format!("{}/journal", self.directory);
+        if !Path::new(&journal_path).exists() {
+            return Ok(());
+        }
+        let journal = match fs::read_to_string(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return Err(format!("Failed to read {}", journal_path))
+        };
+        for line in journal.lines() {
+            match line.split_once('\t') {
+                Some(("created", file)) => self.created.push(file.to_string()),
+                Some(("backup", file)) => self.backed_up.push(file.to_string()),
+                Some(("discard", directory)) => self.discarded.push(directory.to_string()),
+                Some(("symlink", link)) => match link.split_once('\t') {
+                    Some((file, target)) => self.symlinks.push((file.to_string(), target.to_string())),
+                    None => return Err(format!("Malformed transaction journal line \"{}\"", line))
+                },
+                Some(("mtime", modified)) => match modified.rsplit_once('\t').map(|(file, time)| (file, time.parse::<u128>())) {
+                    Some((file, Ok(nanos))) => {
+                        let time = UNIX_EPOCH + Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32);
+                        self.modified.push((file.to_string(), time));
+                    }
+                    _ => return Err(format!("Malformed transaction journal line \"{}\"", line))
+                },
+                _ => return Err(format!("Malformed transaction journal line \"{}\"", line))
+            }
+        }
+        Ok(())
+    }
+
+    fn journal(&self, kind: &str, file: &str) -> Result<(), String> {
+        let journal_path = format!("{}/journal", self.directory);
+        let mut journal = match OpenOptions::new().create(true).append(true).open(&journal_path) {
+            Ok(journal) => journal,
+            Err(_) => return Err(format!("Failed to open {}", journal_path))
+        };
+        match writeln!(journal, "{}\t{}", kind, file) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("Failed to write {}", journal_path))
+        }
+    }
+
+    /// Record the files under the install path that are about to be written, backing up
+    /// any that already exist
+    pub(crate) fn stage_files(&mut self, files: &[&str]) -> Result<(), String> {
+        for file in files {
+            self.stage_path(&format!("{}/{}", self.install_path, file))?;
+        }
+        Ok(())
+    }
+
+    /// Record a file anywhere on disk that is about to be written or removed
+    pub(crate) fn stage_path(&mut self, full_path: &str) -> Result<(), String> {
+        if self.created.iter().any(|item| item == full_path) || self.backed_up.iter().any(|item| item == full_path)
+            || self.symlinks.iter().any(|(item, _)| item == full_path) {
+            return Ok(());
+        }
+        // Links are restored as links rather than as copies of what they point to
+        if let Ok(target) = fs::read_link(full_path) {
+            let target = target.to_string_lossy().to_string();
+            self.journal("symlink", &format!("{}\t{}", full_path, target))?;
+            self.symlinks.push((full_path.to_string(), target));
+        } else if Path::new(full_path).is_file() {
+            copy_file(full_path, &format!("{}/backup/{}", self.directory, self.backed_up.len()))?;
+            self.journal("backup", full_path)?;
+            self.backed_up.push(full_path.to_string());
+        } else {
+            self.journal("created", full_path)?;
+            self.created.push(full_path.to_string());
+        }
+        Ok(())
+    }
+
+    /// Record the modification time of a file that is about to be touched
+    pub(crate) fn stage_modified(&mut self, full_path: &str) -> Result<(), String> {
+        if self.modified.iter().any(|(item, _)| item == full_path) {
+            return Ok(());
+        }
+        let time = match fs::symlink_metadata(full_path).and_then(|metadata| metadata.modified()) {
+            Ok(time) => time,
+            Err(issue) => return Err(format!("Failed to read modification time of {} <- {}", full_path, issue))
+        };
+        let nanos = time.duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or(0);
+        self.journal("mtime", &format!("{}\t{}", full_path, nanos))?;
+        self.modified.push((full_path.to_string(), time));
+        Ok(())
+    }
+
+    /// Record a directory that is about to be replaced and can be extracted again from the
+    /// mod cache, so a rollback removes it instead of backing it up
+    pub(crate) fn discard_on_rollback(&mut self, full_path: &str) -> Result<(), String> {
+        if self.discarded.iter().any(|item| item == full_path) {
+            return Ok(());
+        }
+        self.journal("discard", full_path)?;
+        self.discarded.push(full_path.to_string());
+        Ok(())
+    }
+
+    /// Keep every change
+    pub(crate) fn commit(self) -> Result<(), String> {
+        match fs::remove_dir_all(&self.directory) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("Failed to clean up {}", self.directory))
+        }
+    }
+
+    /// Put the game directory, ownership table and profile back the way they were
+    pub(crate) fn rollback(self) -> Result<(), String> {
+        self.undo()
+    }
+
+    /// The game or config directory a path is inside of, whichever is closest
+    fn staged_under(&self, full_path: &str) -> Option<&Path> {
+        [Path::new(&self.install_path), Path::new(&self.config_path)].iter().copied()
+            .filter(|root| Path::new(full_path).starts_with(root))
+            .max_by_key(|root| root.components().count())
+    }
+
+    fn undo(&self) -> Result<(), String> {
+        for full_path in &self.created {
+            if fs::symlink_metadata(full_path).is_ok() && fs::remove_file(full_path).is_err() {
+                return Err(format!("Failed to remove {}", full_path));
+            }
+            // Clean up directories that only existed for this file, up to the game or config
+            // directory it was staged under
+            let root = match self.staged_under(full_path) {
+                Some(root) => root,
+                None => continue
+            };
+            let mut parent = Path::new(full_path).parent();
+            while let Some(directory) = parent {
+                if directory == root || fs::remove_dir(directory).is_err() {
+                    break;
+                }
+                parent = directory.parent();
+            }
+        }
+        for (position, full_path) in self.backed_up.iter().enumerate() {
+            // The file may have been replaced by a link, which must not be written through
+            if fs::symlink_metadata(full_path).is_ok() && fs::remove_file(full_path).is_err() {
+                return Err(format!("Failed to remove {}", full_path));
+            }
+            copy_file(&format!("{}/backup/{}", self.directory, position), full_path)?;
+        }
+        for (full_path, target) in &self.symlinks {
+            if fs::symlink_metadata(full_path).is_ok() && fs::remove_file(full_path).is_err() {
+                return Err(format!("Failed to remove {}", full_path));
+            }
+            if let Some(parent) = Path::new(full_path).parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            if symlink(Path::new(target), Path::new(full_path)).is_err() {
+                return Err(format!("Failed to restore link {}", full_path));
+            }
+        }
+        // After restoring backups, which would otherwise get the time they were copied at
+        for (full_path, time) in &self.modified {
+            match fs::symlink_metadata(full_path) {
+                Ok(metadata) if metadata.is_file() => {}
+                _ => continue
+            }
+            if fs::File::open(full_path).and_then(|file| file.set_modified(*time)).is_err() {
+                return Err(format!("Failed to restore modification time of {}", full_path));
+            }
+        }
+        for directory in &self.discarded {
+            if Path::new(directory).exists() && fs::remove_dir_all(directory).is_err() {
+                return Err(format!("Failed to remove {}", directory));
+            }
+        }
+        let profile_snapshot = format!("{}/profile", self.directory);
+        if Path::new(&profile_snapshot).exists() {
+            copy_file(&profile_snapshot, &self.profile_path)?;
+        }
+        let ownership_snapshot = format!("{}/file_ownership.json", self.directory);
+        if Path::new(&ownership_snapshot).exists() {
+            copy_file(&ownership_snapshot, &self.ownership_path)?;
+        } else if Path::new(&profile_snapshot).exists() && Path::new(&self.ownership_path).exists() {
+            // There was no ownership table before the transaction began
+            if fs::remove_file(&self.ownership_path).is_err() {
+                return Err(format!("Failed to remove {}", self.ownership_path));
+            }
+        }
+        match fs::remove_dir_all(&self.directory) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("Failed to clean up {}", self.directory))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_removes_created_directories_up_to_their_root_only() {
+        let base = std::env::temp_dir().join(format!("pipboy-transaction-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let base = base.to_string_lossy().to_string();
+        let config_path = format!("{}/config", base);
+        let install_path = format!("{}/game", base);
+        let outside = format!("{}/outside/nested/file", base);
+        let staged = format!("{}/staging/someone/mod/Data/file", config_path);
+        let deployed = format!("{}/Data/textures/file", install_path);
+        for path in [&outside, &staged, &deployed] {
+            fs::create_dir_all(Path::new(path).parent().unwrap()).unwrap();
+        }
+        let transaction = Transaction {
+            directory: format!("{}/transactions/default", config_path),
+            config_path: config_path.clone(),
+            install_path: install_path.clone(),
+            profile_path: format!("{}/profiles/default/profile", config_path),
+            ownership_path: format!("{}/profiles/default/file_ownership.json", config_path),
+            created: vec![outside.clone(), staged.clone(), deployed.clone()],
+            backed_up: Vec::new(),
+            symlinks: Vec::new(),
+            discarded: Vec::new(),
+            modified: Vec::new(),
+        };
+        fs::create_dir_all(&transaction.directory).unwrap();
+        for path in [&outside, &staged, &deployed] {
+            fs::write(path, "").unwrap();
+        }
+        transaction.undo().unwrap();
+        assert!(!Path::new(&staged).exists());
+        assert!(!Path::new(&format!("{}/staging", config_path)).exists());
+        assert!(Path::new(&config_path).exists());
+        assert!(!Path::new(&format!("{}/Data", install_path)).exists());
+        assert!(Path::new(&install_path).exists());
+        // Paths outside both roots are removed, but their directories are left alone
+        assert!(!Path::new(&outside).exists());
+        assert!(Path::new(&format!("{}/outside/nested", base)).exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn rollback_restores_modification_times() {
+        let base = std::env::temp_dir().join(format!("pipboy-transaction-mtime-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let base = base.to_string_lossy().to_string();
+        let config_path = format!("{}/config", base);
+        let plugin = format!("{}/game/Data/a.esp", base);
+        fs::create_dir_all(Path::new(&plugin).parent().unwrap()).unwrap();
+        fs::write(&plugin, "").unwrap();
+        let original = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        fs::File::open(&plugin).unwrap().set_modified(original).unwrap();
+        let mut transaction = Transaction {
+            directory: format!("{}/transactions/default", config_path),
+            config_path: config_path.clone(),
+            install_path: format!("{}/game", base),
+            profile_path: format!("{}/profiles/default/profile", config_path),
+            ownership_path: format!("{}/profiles/default/file_ownership.json", config_path),
+            created: Vec::new(),
+            backed_up: Vec::new(),
+            symlinks: Vec::new(),
+            discarded: Vec::new(),
+            modified: Vec::new(),
+        };
+        fs::create_dir_all(&transaction.directory).unwrap();
+        transaction.stage_modified(&plugin).unwrap();
+        fs::File::open(&plugin).unwrap().set_modified(UNIX_EPOCH).unwrap();
+        // Roll back from the journal, as after a crash
+        let mut interrupted = Transaction { modified: Vec::new(), ..transaction };
+        interrupted.load_journal().unwrap();
+        interrupted.undo().unwrap();
+        assert_eq!(fs::metadata(&plugin).unwrap().modified().unwrap(), original);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::config_file;
+
+/// Path to the map of repository to trusted public key
+fn trust_store_path(config_path: &str) -> String {
+    format!("{}/trusted_keys.json", config_path)
+}
+
+/// Decode a hex string into a fixed number of bytes
+fn decode_hex<const N: usize>(text: &str) -> Result<[u8; N], String> {
+    let text = text.trim();
+    if text.len() != N * 2 || !text.is_ascii() {
+        return Err(format!("Expected {} hex characters", N * 2));
+    }
+    let mut bytes = [0u8; N];
+    for (position, byte) in bytes.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&text[position * 2..position * 2 + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => return Err(format!("\"{}\" is not valid hex", text))
+        };
+    }
+    Ok(bytes)
+}
+
+/// Parse a hex encoded ed25519 public key
+fn parse_key(key: &str) -> Result<VerifyingKey, String> {
+    match VerifyingKey::from_bytes(&decode_hex::<32>(key)?) {
+        Ok(key) => Ok(key),
+        Err(issue) => Err(format!("Invalid ed25519 public key <- {}", issue))
+    }
+}
+
+pub(crate) fn load_trust_store(config_path: &str) -> Result<HashMap<String, String>, String> {
+    let store_path = trust_store_path(config_path);
+    if !Path::new(&store_path).exists() {
+        return Ok(HashMap::new());
+    }
+    let j = match fs::read_to_string(&store_path) {
+        Ok(j) => j,
+        Err(_) => return Err(format!("Failed to read {}", store_path))
+    };
+    match serde_json::from_str(&j) {
+        Ok(store) => Ok(store),
+        Err(issue) => Err(format!("Malformed trust store <- {}", issue))
+    }
+}
+
+fn save_trust_store(config_path: &str, store: &HashMap<String, String>) -> Result<(), String> {
+    let j = serde_json::to_string_pretty(store).unwrap();
+    match fs::write(trust_store_path(config_path), j) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Failed to write trust store to disk.".to_string())
+    }
+}
+
+/// Trust indices of a repository that are signed by the given key
+pub(crate) fn trust_repository(config_path: &str, remote: &str, key: &str) -> Result<(), String> {
+    let remotes = match config_file::load_config_file(config_path) {
+        Ok(config) => config.repository_list,
+        Err(_) => return Err("Failed to load configuration file!".to_string())
+    };
+    if !remotes.iter().any(|item| item == remote) {
+        return Err(format!("{} is not in the repository list", remote));
+    }
+    parse_key(key)?;
+    let mut store = load_trust_store(config_path)?;
+    store.insert(remote.to_string(), key.trim().to_lowercase());
+    save_trust_store(config_path, &store)
+}
+
+/// Stop trusting a repository
+pub(crate) fn untrust_repository(config_path: &str, remote: &str) -> Result<(), String> {
+    let mut store = load_trust_store(config_path)?;
+    if store.remove(remote).is_none() {
+        return Err(format!("{} is not trusted", remote));
+    }
+    save_trust_store(config_path, &store)
+}
+
+/// Print every repository along with the key it is trusted with
+pub(crate) fn list_repositories(config_path: &str, remotes: &[String]) -> Result<(), String> {
+    let store = load_trust_store(config_path)?;
+    for remote in remotes {
+        match store.get(remote) {
+            Some(key) => println!("{} [trusted: {}]", remote, key),
+            None => println!("{} [untrusted]", remote)
+        }
+    }
+    Ok(())
+}
+
+/// Verify the signature of a repository index against the repository's trusted key
+/// # Arguments
+/// 1. body - The index exactly as it was served
+/// 2. signature - Hex encoded ed25519 signature served at `index.json.sig`
+pub(crate) fn verify_index(config_path: &str, remote: &str, body: &[u8], signature: &str) -> Result<(), String> {
+    let store = load_trust_store(config_path)?;
+    let key = match store.get(remote) {
+        Some(key) => parse_key(key)?,
+        None => return Err(format!("{} is not trusted. Add its key with: pipboy repo trust {} <key>", remote, remote))
+    };
+    let signature = Signature::from_bytes(&decode_hex::<64>(signature)?);
+    match key.verify_strict(body, &signature) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("The index signature of {} does not verify", remote))
+    }
+}
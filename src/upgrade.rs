@@ -0,0 +1,203 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use semver::{Version, VersionReq};
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::digest;
+use super::mods;
+use super::profile::ProfileFile;
+use super::remote;
+use super::versions;
+use super::versions::ModSpec;
+
+/// An installed mod that a repository serves a different version of
+pub(crate) struct Upgrade {
+    pub(crate) old_value: String,
+    pub(crate) new_value: String,
+    /// Dependency declarations of the new version
+    pub(crate) depends: Vec<String>,
+    /// The repository serves a different build of the installed version
+    pub(crate) rebuild: bool,
+    /// Go back to the build of the installed version that the last rebuild replaced
+    pub(crate) previous: bool,
+}
+
+/// Whether the cached tarball of a mod differs from the build its repository serves
+fn is_rebuilt(config_path: &str, mod_value: &str, sha256: &str) -> bool {
+    let tarball_path = format!("{}/mods/cached/{}/mod.tar.gz", config_path, mods::mod_directory(mod_value));
+    if sha256.is_empty() || !Path::new(&tarball_path).exists() {
+        return false;
+    }
+    match digest::sha256_file(&tarball_path) {
+        Ok(actual) => actual != sha256.to_lowercase(),
+        Err(_) => false
+    }
+}
+
+/// Find installed mods that have a different version available
+/// # Arguments
+/// 1. requested - Mods to upgrade. Every installed mod is checked if this is empty.
+///    Naming a version here allows downgrading, which is how an upgrade is rolled back.
+pub(crate) fn find_upgrades(config_path: &str, remotes: &[String], profile: &ProfileFile, requested: &[ModSpec]) -> Result<Vec<Upgrade>, String> {
+    let installed = profile.installed_versions();
+    let mut specs = Vec::new();
+    if requested.is_empty() {
+        for mod_name in &profile.enabled_mods {
+            specs.push(ModSpec { name: mod_name.clone(), requirement: VersionReq::STAR });
+        }
+    } else {
+        for spec in requested {
+            if !installed.contains_key(&spec.name) {
+                return Err(format!("{} isn't installed!", spec.name));
+            }
+            specs.push(ModSpec { name: spec.name.clone(), requirement: spec.requirement.clone() });
+        }
+    }
+    let mut upgrades = Vec::new();
+    for spec in specs {
+        let newest = remote::find_installable_versions(config_path, remotes, &spec.name).into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| versions::satisfies(&entry.parsed_version(), &spec.requirement))
+            .max_by(|a, b| a.parsed_version().cmp(&b.parsed_version()));
+        let newest = match newest {
+            Some(entry) => entry,
+            None if requested.is_empty() => continue,
+            None => return Err(format!("No available version of {} satisfies {}", spec.name, spec.requirement))
+        };
+        let current = &installed[&spec.name];
+        let target = newest.parsed_version();
+        let old_value = profile.installed_value(&spec.name);
+        let rebuild = target == *current && is_rebuilt(config_path, &old_value, &newest.sha256);
+        // Only move backwards when the user asked for a specific version
+        if (target == *current && !rebuild) || (requested.is_empty() && target < *current) {
+            continue;
+        }
+        upgrades.push(Upgrade {
+            old_value,
+            new_value: newest.versioned_value(),
+            depends: newest.depends,
+            rebuild,
+            previous: false,
+        });
+    }
+    Ok(upgrades)
+}
+
+/// Find installed mods whose build from before a repository rebuild is still cached, to
+/// roll those rebuilds back
+/// # Arguments
+/// 1. requested - Mods to roll back. Every installed mod with a previous build is used if this is empty.
+pub(crate) fn find_previous_builds(config_path: &str, profile: &ProfileFile, requested: &[ModSpec]) -> Result<Vec<Upgrade>, String> {
+    let names: Vec<String> = if requested.is_empty() {
+        profile.enabled_mods.clone()
+    } else {
+        requested.iter().map(|spec| spec.name.clone()).collect()
+    };
+    let mut upgrades = Vec::new();
+    for mod_name in names {
+        if !profile.enabled_mods.contains(&mod_name) {
+            return Err(format!("{} isn't installed!", mod_name));
+        }
+        let value = profile.installed_value(&mod_name);
+        if !Path::new(&mods::previous_build_path(config_path, &value)).exists() {
+            if requested.is_empty() {
+                continue;
+            }
+            return Err(format!("No previous build of {} is cached", value));
+        }
+        upgrades.push(Upgrade {
+            old_value: value.clone(),
+            new_value: value,
+            // Only the dependencies of the build being replaced are known
+            depends: profile.mod_depends.get(&mod_name).cloned().unwrap_or_default(),
+            rebuild: true,
+            previous: true,
+        });
+    }
+    Ok(upgrades)
+}
+
+/// Drop upgrades that would leave an installed mod's dependency unsatisfied
+///
+/// Returns the reason each dropped upgrade was held back.
+pub(crate) fn check_upgrades(profile: &ProfileFile, upgrades: &mut Vec<Upgrade>) -> Vec<String> {
+    let mut held_back = Vec::new();
+    loop {
+        // Versions and dependencies as they would be after every remaining upgrade
+        let mut final_versions: HashMap<String, Option<Version>> = profile.installed_versions();
+        let mut final_depends: HashMap<String, Vec<String>> = profile.mod_depends.clone();
+        for upgrade in upgrades.iter() {
+            let mod_name = mods::mod_name(&upgrade.new_value).to_string();
+            let version = mods::mod_version(&upgrade.new_value).and_then(|version| Version::parse(version).ok());
+            final_versions.insert(mod_name.clone(), version);
+            final_depends.insert(mod_name, upgrade.depends.clone());
+        }
+        let mut broken = None;
+        'search: for (mod_name, depends) in &final_depends {
+            if !final_versions.contains_key(mod_name) {
+                continue;
+            }
+            for dependency in depends {
+                let spec = match versions::parse_spec(dependency) {
+                    Ok(spec) => spec,
+                    Err(_) => continue
+                };
+                let satisfied = match final_versions.get(&spec.name) {
+                    Some(version) => versions::satisfies(version, &spec.requirement),
+                    None => false
+                };
+                if satisfied {
+                    continue;
+                }
+                // Blame the upgrade of the dependency if there is one, otherwise the dependent
+                let position = upgrades.iter().position(|upgrade| mods::mod_name(&upgrade.new_value) == spec.name)
+                    .or_else(|| upgrades.iter().position(|upgrade| mods::mod_name(&upgrade.new_value) == mod_name));
+                if let Some(position) = position {
+                    broken = Some((position, format!("{} requires {}", mod_name, dependency)));
+                    break 'search;
+                }
+            }
+        }
+        match broken {
+            Some((position, reason)) => {
+                let upgrade = upgrades.remove(position);
+                held_back.push(format!("{} -> {} held back: {}", upgrade.old_value, upgrade.new_value, reason));
+            }
+            None => return held_back
+        }
+    }
+}
+
+/// Print the mods that are out of date
+pub(crate) fn print_upgrades(upgrades: &[Upgrade]) {
+    if upgrades.is_empty() {
+        println!("Every mod is up to date.");
+        return;
+    }
+    println!("Outdated mods:");
+    for upgrade in upgrades {
+        if upgrade.previous {
+            println!("    {} (back to the build before its rebuild)", upgrade.old_value);
+        } else if upgrade.rebuild {
+            println!("    {} (rebuilt by its repository)", upgrade.old_value);
+        } else {
+            println!("    {} -> {}", upgrade.old_value, upgrade.new_value);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2021 Aayla Semyonova
+// 
+// This file is part of pipboy.
+// 
+// pipboy is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// pipboy is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with pipboy.  If not, see <http://www.gnu.org/licenses/>.
+
+use semver::{Version, VersionReq};
+
+/// A mod along with the versions of it that are acceptable
+pub(crate) struct ModSpec {
+    pub(crate) name: String,
+    pub(crate) requirement: VersionReq,
+}
+
+/// Parse a mod from the command line or a dependency declaration
+///
+/// Accepts `author/mod`, `author/mod@1.2.3` (exactly that version), `author/mod@1.2`
+/// (any version compatible with 1.2) and `author/mod@>=1.2, <2`. Dependency declarations
+/// may also separate the requirement with whitespace instead of `@`.
+pub(crate) fn parse_spec(spec: &str) -> Result<ModSpec, String> {
+    let spec = spec.trim();
+    let (name, requirement) = match spec.find(|c: char| c == '@' || c.is_whitespace()) {
+        Some(position) => (&spec[..position], spec[position + 1..].trim()),
+        None => (spec, "")
+    };
+    let parts: Vec<&str> = name.split('/').collect();
+    if parts.len() != 2 || parts.iter().any(|part| part.is_empty()) {
+        return Err(format!("\"{}\" is not formatted author/mod", name));
+    }
+    let requirement = if requirement.is_empty() {
+        VersionReq::STAR
+    } else if let Ok(version) = Version::parse(requirement) {
+        // A complete version pins the mod to exactly that version
+        match VersionReq::parse(&format!("={}", version)) {
+            Ok(requirement) => requirement,
+            Err(issue) => return Err(format!("Invalid version {} <- {}", version, issue))
+        }
+    } else {
+        match VersionReq::parse(requirement) {
+            Ok(requirement) => requirement,
+            Err(issue) => return Err(format!("Invalid version requirement \"{}\" <- {}", requirement, issue))
+        }
+    };
+    Ok(ModSpec { name: name.to_string(), requirement })
+}
+
+/// Whether an optional version meets a requirement. Unversioned mods only meet `*`.
+pub(crate) fn satisfies(version: &Option<Version>, requirement: &VersionReq) -> bool {
+    match version {
+        Some(version) => requirement.matches(version),
+        None => *requirement == VersionReq::STAR
+    }
+}
+
+/// Build a mod value that refers to one exact version of a mod
+pub(crate) fn versioned_value(name: &str, version: &Option<Version>) -> String {
+    match version {
+        Some(version) => format!("{}@{}", name, version),
+        None => name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(spec: &str) -> String {
+        parse_spec(spec).unwrap().requirement.to_string()
+    }
+
+    #[test]
+    fn plain_names_accept_any_version() {
+        let spec = parse_spec("someone/mod").unwrap();
+        assert_eq!(spec.name, "someone/mod");
+        assert_eq!(spec.requirement, VersionReq::STAR);
+    }
+
+    #[test]
+    fn complete_versions_are_pinned() {
+        assert_eq!(requirement("someone/mod@1.2.3"), "=1.2.3");
+    }
+
+    #[test]
+    fn partial_versions_and_ranges_are_requirements() {
+        assert_eq!(requirement("someone/mod@1.2"), "^1.2");
+        assert_eq!(requirement("someone/mod@>=1.2, <2"), ">=1.2, <2");
+        assert_eq!(requirement("  someone/mod >=1.2  "), ">=1.2");
+    }
+
+    #[test]
+    fn malformed_specs_are_rejected() {
+        assert!(parse_spec("mod").is_err());
+        assert!(parse_spec("someone/").is_err());
+        assert!(parse_spec("a/b/c").is_err());
+        assert!(parse_spec("someone/mod@banana").is_err());
+    }
+
+    #[test]
+    fn unversioned_mods_only_meet_any_version() {
+        assert!(satisfies(&None, &VersionReq::STAR));
+        assert!(!satisfies(&None, &VersionReq::parse("^1").unwrap()));
+        assert!(satisfies(&Version::parse("1.4.0").ok(), &VersionReq::parse("^1").unwrap()));
+    }
+}